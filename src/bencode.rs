@@ -1,93 +1,85 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 
-use serde_json::Value;
+pub use borrowed::{validate, BencodeRef, ValueRef};
+pub use de::{from_bytes, Deserializer};
+pub use query::{Query, QueryError};
+pub use ser::{to_bencode, to_bytes, Serializer};
+pub use stream::{BencodeDecoder, DecodeError};
 
-#[derive(Debug, PartialEq, Eq)]
+mod borrowed;
+mod de;
+mod json;
+mod query;
+mod ser;
+mod stream;
+
+/// Deepest nesting of lists and dictionaries accepted by default, so hostile input can't run the
+/// parsers out of stack.
+const MAX_DEPTH: usize = 64;
+
+/// A decoded bencode value. Dictionaries keep their keys sorted as raw bytes, which is the order
+/// the encoder must emit them in.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Bencode {
-    String(String),
+    Bytes(Vec<u8>),
     Number(isize),
     List(Vec<Bencode>),
-    Dictionary(HashMap<String, Bencode>),
+    Dictionary(BTreeMap<Vec<u8>, Bencode>),
 }
 
 impl Bencode {
-    pub fn new(encoded_value: &str) -> Result<Self, BencodeError> {
-        match encoded_value.chars().next() {
-            Some('0'..='9') => {
-                let (number_string, string) = encoded_value
-                    .split_once(':')
-                    .ok_or(BencodeError::MissingDelimeter)?;
-                let number = number_string
-                    .parse()
-                    .map_err(|_| BencodeError::InvalidNumber)?;
-                let decoded_value = string.get(..number).ok_or(BencodeError::InvalidLength)?;
-                Ok(Self::String(decoded_value.to_string()))
-            }
-            Some('i') => {
-                let end = encoded_value
-                    .find('e')
-                    .ok_or(BencodeError::MissingDelimeter)?;
-                let number = encoded_value
-                    .get(1..end)
-                    .expect("is within bounds")
-                    .parse()
-                    .map_err(|_| BencodeError::InvalidNumber)?;
-                Ok(Self::Number(number))
+    pub fn new(encoded_value: &[u8]) -> Result<Self, BencodeError> {
+        BencodeRef::new(encoded_value).map(|value| (&value).into())
+    }
+
+    /// Like [`Bencode::new`], but only accepts canonical input. See [`BencodeRef::strict`].
+    pub fn strict(encoded_value: &[u8]) -> Result<Self, ParseError> {
+        BencodeRef::strict(encoded_value).map(|value| (&value).into())
+    }
+
+    /// Encodes the value in its canonical form: dictionary keys sorted as raw bytes and integers
+    /// without leading zeros.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(self.encoded_length());
+        self.encode_to(&mut encoded);
+        encoded
+    }
+
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Bytes(bytes) => encode_bytes(bytes, out),
+            Bencode::Number(number) => {
+                out.push(b'i');
+                out.extend(number.to_string().as_bytes());
+                out.push(b'e');
             }
-            Some('l') => {
-                let mut decoded_values = Vec::new();
-                let mut rest = &encoded_value[1..];
-
-                loop {
-                    match rest.chars().next() {
-                        Some('e') => break,
-                        None => return Err(BencodeError::MissingDelimeter),
-                        _ => {
-                            let value = Self::new(rest)?;
-                            rest = &rest[value.encoded_length()..];
-                            decoded_values.push(value);
-                        }
-                    }
+            Bencode::List(values) => {
+                out.push(b'l');
+                for value in values {
+                    value.encode_to(out);
                 }
-
-                Ok(Self::List(decoded_values))
+                out.push(b'e');
             }
-            Some('d') => {
-                let mut decoded_values = HashMap::new();
-                let mut rest = &encoded_value[1..];
-
-                loop {
-                    match rest.chars().next() {
-                        Some('e') => break,
-                        None => return Err(BencodeError::MissingDelimeter),
-                        _ => {
-                            let key = Self::new(rest)?;
-                            rest = &rest[key.encoded_length()..];
-                            let Self::String(key) = key else {
-                                return Err(BencodeError::InvalidKey);
-                            };
-                            let value = Self::new(rest)?;
-                            rest = &rest[value.encoded_length()..];
-                            decoded_values.insert(key, value);
-                        }
-                    }
+            Bencode::Dictionary(dictionary) => {
+                out.push(b'd');
+                for (key, value) in dictionary {
+                    encode_bytes(key, out);
+                    value.encode_to(out);
                 }
-
-                Ok(Self::Dictionary(decoded_values))
+                out.push(b'e');
             }
-            Some(c) => unreachable!("Invalid delimeter {c}"),
-            None => Err(BencodeError::EmptyInput),
         }
     }
 
     fn encoded_length(&self) -> usize {
         match self {
-            Bencode::String(s) => s.len() + s.len().to_string().len() + 1,
+            Bencode::Bytes(b) => b.len() + b.len().to_string().len() + 1,
             Bencode::Number(n) => n.to_string().len() + 2,
             Bencode::List(l) => l.iter().map(|v| v.encoded_length()).sum::<usize>() + 2,
             Bencode::Dictionary(d) => {
                 d.iter()
-                    .map(|(k, v)| k.len() + 2 + v.encoded_length())
+                    .map(|(k, v)| k.len() + k.len().to_string().len() + 1 + v.encoded_length())
                     .sum::<usize>()
                     + 2
             }
@@ -95,23 +87,62 @@ impl Bencode {
     }
 }
 
-impl From<&Bencode> for Value {
-    fn from(value: &Bencode) -> Self {
-        match value {
-            Bencode::String(string) => Value::String(string.to_owned()),
-            Bencode::Number(number) => Value::Number((*number).into()),
-            Bencode::List(values) => Value::Array(values.iter().map(Into::into).collect()),
-            Bencode::Dictionary(dictionary) => Value::Object(
-                dictionary
-                    .iter()
-                    .map(|(k, v)| (k.to_owned(), v.into()))
-                    .collect(),
-            ),
-        }
+impl From<&str> for Bencode {
+    fn from(value: &str) -> Self {
+        Self::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Bencode {
+    fn from(value: String) -> Self {
+        Self::Bytes(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Bencode {
+    fn from(value: &[u8]) -> Self {
+        Self::Bytes(value.to_vec())
     }
 }
 
-#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+impl From<Vec<u8>> for Bencode {
+    fn from(value: Vec<u8>) -> Self {
+        Self::Bytes(value)
+    }
+}
+
+impl From<isize> for Bencode {
+    fn from(value: isize) -> Self {
+        Self::Number(value)
+    }
+}
+
+impl From<Vec<Bencode>> for Bencode {
+    fn from(value: Vec<Bencode>) -> Self {
+        Self::List(value)
+    }
+}
+
+impl<K: Into<Vec<u8>>> FromIterator<(K, Bencode)> for Bencode {
+    fn from_iter<I: IntoIterator<Item = (K, Bencode)>>(iter: I) -> Self {
+        Self::Dictionary(iter.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+fn parse_number<T: std::str::FromStr>(bytes: &[u8]) -> Result<T, BencodeError> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|string| string.parse().ok())
+        .ok_or(BencodeError::InvalidNumber)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
 pub enum BencodeError {
     #[error("input is empty")]
     EmptyInput,
@@ -123,6 +154,77 @@ pub enum BencodeError {
     InvalidLength,
     #[error("invalid key")]
     InvalidKey,
+    #[error("unexpected byte {:?}", *.0 as char)]
+    UnexpectedByte(u8),
+    #[error("number has leading zeros")]
+    LeadingZero,
+    #[error("negative zero")]
+    NegativeZero,
+    #[error("dictionary keys are not sorted")]
+    UnsortedKeys,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+    #[error("trailing data after value")]
+    TrailingData,
+    #[error("input ends in the middle of a value")]
+    Truncated,
+    #[error("value is nested too deeply")]
+    DepthLimit,
+    #[error("value is too large")]
+    SizeLimit,
+    #[error("{0} is not supported by bencode")]
+    Unsupported(&'static str),
+    #[error("{0}")]
+    Custom(String),
+}
+
+/// A [`BencodeError`] together with where in the input it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: BencodeError,
+    pub offset: usize,
+    pub path: Path,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " ({})", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Location of a value inside nested dictionaries and lists, displayed as `info.files[3].length`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Path(pub Vec<PathSegment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+impl Path {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{}", key.escape_ascii())?,
+                PathSegment::Key(key) => write!(f, ".{}", key.escape_ascii())?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -132,41 +234,41 @@ mod tests {
     #[test]
     fn decodes_string() {
         assert_eq!(
-            Bencode::new("5:hello"),
-            Ok(Bencode::String("hello".to_string()))
+            Bencode::new(b"5:hello"),
+            Ok(Bencode::Bytes(b"hello".to_vec()))
         );
-        assert_eq!(Bencode::new("5hello"), Err(BencodeError::MissingDelimeter));
-        assert_eq!(Bencode::new("5a:hello"), Err(BencodeError::InvalidNumber));
-        assert_eq!(Bencode::new("6:hello"), Err(BencodeError::InvalidLength));
+        assert_eq!(Bencode::new(b"5hello"), Err(BencodeError::MissingDelimeter));
+        assert_eq!(Bencode::new(b"5a:hello"), Err(BencodeError::InvalidNumber));
+        assert_eq!(Bencode::new(b"6:hello"), Err(BencodeError::InvalidLength));
     }
 
     #[test]
     fn decodes_number() {
-        assert_eq!(Bencode::new("i52e"), Ok(Bencode::Number(52)));
-        assert_eq!(Bencode::new("i-52e"), Ok(Bencode::Number(-52)));
-        assert_eq!(Bencode::new("i52"), Err(BencodeError::MissingDelimeter));
-        assert_eq!(Bencode::new("i52ae"), Err(BencodeError::InvalidNumber));
+        assert_eq!(Bencode::new(b"i52e"), Ok(Bencode::Number(52)));
+        assert_eq!(Bencode::new(b"i-52e"), Ok(Bencode::Number(-52)));
+        assert_eq!(Bencode::new(b"i52"), Err(BencodeError::MissingDelimeter));
+        assert_eq!(Bencode::new(b"i52ae"), Err(BencodeError::InvalidNumber));
     }
 
     #[test]
     fn decodes_list() {
         assert_eq!(
-            Bencode::new("l5:helloi52ee"),
+            Bencode::new(b"l5:helloi52ee"),
             Ok(Bencode::List(vec![
-                Bencode::String("hello".to_string()),
+                Bencode::Bytes(b"hello".to_vec()),
                 Bencode::Number(52)
             ]))
         );
         assert_eq!(
-            Bencode::new("l5:helloi52e"),
+            Bencode::new(b"l5:helloi52e"),
             Err(BencodeError::MissingDelimeter)
         );
         assert_eq!(
-            Bencode::new("l5a:helloi52ee"),
+            Bencode::new(b"l5a:helloi52ee"),
             Err(BencodeError::InvalidNumber)
         );
         assert_eq!(
-            Bencode::new("l5:helloi52aee"),
+            Bencode::new(b"l5:helloi52aee"),
             Err(BencodeError::InvalidNumber)
         );
     }
@@ -174,42 +276,42 @@ mod tests {
     #[test]
     fn decodes_dictionary() {
         assert_eq!(
-            Bencode::new("d3:foo3:bar5:helloi52ee"),
-            Ok(Bencode::Dictionary(HashMap::from([
-                ("foo".to_string(), Bencode::String("bar".to_string())),
-                ("hello".to_string(), Bencode::Number(52))
+            Bencode::new(b"d3:foo3:bar5:helloi52ee"),
+            Ok(Bencode::Dictionary(BTreeMap::from([
+                (b"foo".to_vec(), Bencode::Bytes(b"bar".to_vec())),
+                (b"hello".to_vec(), Bencode::Number(52))
             ])))
         );
         assert_eq!(
-            Bencode::new("d3:foo3:bar5:helloi52e"),
+            Bencode::new(b"d3:foo3:bar5:helloi52e"),
             Err(BencodeError::MissingDelimeter)
         );
         assert_eq!(
-            Bencode::new("d3:foo3:bari52e5:hello"),
+            Bencode::new(b"d3:foo3:bari52e5:hello"),
             Err(BencodeError::InvalidKey)
         );
         assert_eq!(
-            Bencode::new("d3a:foo3:bar5:helloi52ee"),
+            Bencode::new(b"d3a:foo3:bar5:helloi52ee"),
             Err(BencodeError::InvalidNumber)
         );
         assert_eq!(
-            Bencode::new("d3:foo3:bar5:helloi52aee"),
+            Bencode::new(b"d3:foo3:bar5:helloi52aee"),
             Err(BencodeError::InvalidNumber)
         );
 
         // {"inner_dict":{"key1":"value1","key2":42,"list_key":["item1","item2",3]}}
         assert_eq!(
-            Bencode::new("d10:inner_dictd4:key16:value14:key2i42e8:list_keyl5:item15:item2i3eeee"),
-            Ok(Bencode::Dictionary(HashMap::from([(
-                "inner_dict".to_string(),
-                Bencode::Dictionary(HashMap::from([
-                    ("key1".to_string(), Bencode::String("value1".to_string())),
-                    ("key2".to_string(), Bencode::Number(42)),
+            Bencode::new(b"d10:inner_dictd4:key16:value14:key2i42e8:list_keyl5:item15:item2i3eeee"),
+            Ok(Bencode::Dictionary(BTreeMap::from([(
+                b"inner_dict".to_vec(),
+                Bencode::Dictionary(BTreeMap::from([
+                    (b"key1".to_vec(), Bencode::Bytes(b"value1".to_vec())),
+                    (b"key2".to_vec(), Bencode::Number(42)),
                     (
-                        "list_key".to_string(),
+                        b"list_key".to_vec(),
                         Bencode::List(vec![
-                            Bencode::String("item1".to_string()),
-                            Bencode::String("item2".to_string()),
+                            Bencode::Bytes(b"item1".to_vec()),
+                            Bencode::Bytes(b"item2".to_vec()),
                             Bencode::Number(3),
                         ])
                     ),
@@ -217,4 +319,68 @@ mod tests {
             ),])))
         );
     }
+
+    #[test]
+    fn decodes_binary_string() {
+        assert_eq!(
+            Bencode::new(b"4:\xff\x00\x9a\x10"),
+            Ok(Bencode::Bytes(vec![0xff, 0x00, 0x9a, 0x10]))
+        );
+        assert_eq!(
+            Bencode::new(b"d6:pieces2:\xde\xad4:spami1ee"),
+            Ok(Bencode::Dictionary(BTreeMap::from([
+                (b"pieces".to_vec(), Bencode::Bytes(vec![0xde, 0xad])),
+                (b"spam".to_vec(), Bencode::Number(1)),
+            ])))
+        );
+    }
+
+    #[test]
+    fn renders_json() {
+        use serde_json::Value;
+
+        let value = Bencode::new(b"l5:hello2:\xff\xfei52ee").unwrap();
+        assert_eq!(
+            Value::from(&value),
+            serde_json::json!(["hello", {"$hex": "fffe"}, 52])
+        );
+    }
+
+    #[test]
+    fn encodes_canonically() {
+        let value: Bencode = [
+            ("zebra", Bencode::from("last")),
+            ("apple", Bencode::Number(-7)),
+            (
+                "list",
+                Bencode::from(vec![Bencode::from("a"), Bencode::Number(0)]),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            value.encode(),
+            b"d5:applei-7e4:listl1:ai0ee5:zebra4:laste".to_vec()
+        );
+
+        // Keys are ordered as raw bytes, not as UTF-8 text or by insertion order.
+        let value: Bencode = [
+            (b"\xff".to_vec(), Bencode::Number(1)),
+            (b"Z".to_vec(), Bencode::Number(2)),
+            (b"a".to_vec(), Bencode::Number(3)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(value.encode(), b"d1:Zi2e1:ai3e1:\xffi1ee".to_vec());
+    }
+
+    #[test]
+    fn round_trips() {
+        let encoded: &[u8] =
+            b"d8:announce3:url4:infod6:lengthi92063e4:name4:file6:pieces3:\x00\xff\x10ee";
+        assert_eq!(Bencode::new(encoded).unwrap().encode(), encoded);
+
+        let encoded: &[u8] = b"d1:ad1:bl1:ci-12eee1:di0ee";
+        assert_eq!(Bencode::new(encoded).unwrap().encode(), encoded);
+    }
 }
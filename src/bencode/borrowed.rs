@@ -0,0 +1,390 @@
+use std::ops::Range;
+
+use super::{parse_number, Bencode, BencodeError, ParseError, Path, PathSegment, MAX_DEPTH};
+
+/// A bencode value borrowed from the buffer it was parsed from.
+///
+/// Every value remembers the byte span it occupies in the original input, so callers can get at
+/// the exact encoded bytes of any nested value (e.g. to hash the `info` dictionary of a torrent).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BencodeRef<'a> {
+    raw: &'a [u8],
+    span: Range<usize>,
+    value: ValueRef<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueRef<'a> {
+    Bytes(&'a [u8]),
+    Number(isize),
+    List(Vec<BencodeRef<'a>>),
+    /// Entries in the order they appear in the input.
+    Dictionary(Vec<(&'a [u8], BencodeRef<'a>)>),
+}
+
+impl<'a> BencodeRef<'a> {
+    /// Parses the value at the start of `input`. Any data following it is ignored.
+    pub fn new(input: &'a [u8]) -> Result<Self, BencodeError> {
+        Parser::new(input, false).value().map_err(|e| e.kind)
+    }
+
+    /// Like [`new`](Self::new), but with lists and dictionaries allowed to nest `max_depth` deep
+    /// rather than the default of 64.
+    pub fn with_max_depth(input: &'a [u8], max_depth: usize) -> Result<Self, BencodeError> {
+        let mut parser = Parser::new(input, false);
+        parser.max_depth = max_depth;
+        parser.value().map_err(|e| e.kind)
+    }
+
+    /// Parses `input` as a single canonical bencode value, rejecting leading zeros, `i-0e`,
+    /// unsorted or duplicate dictionary keys and trailing data.
+    pub fn strict(input: &'a [u8]) -> Result<Self, ParseError> {
+        let mut parser = Parser::new(input, true);
+        let value = parser.document();
+        match parser.violations.into_iter().next() {
+            Some(violation) => Err(violation),
+            None => value,
+        }
+    }
+
+    pub fn value(&self) -> &ValueRef<'a> {
+        &self.value
+    }
+
+    /// The byte range of this value within the original input.
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    /// The encoded bytes of this value, exactly as they appear in the original input.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.value {
+            ValueRef::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<isize> {
+        match self.value {
+            ValueRef::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeRef<'a>]> {
+        match &self.value {
+            ValueRef::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this value is a dictionary. If the key is repeated the last entry wins,
+    /// matching what decoding into a [`Bencode`] does.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&BencodeRef<'a>> {
+        let ValueRef::Dictionary(entries) = &self.value else {
+            return None;
+        };
+        entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key.as_ref())
+            .map(|(_, v)| v)
+    }
+}
+
+impl From<&BencodeRef<'_>> for Bencode {
+    fn from(value: &BencodeRef<'_>) -> Self {
+        match &value.value {
+            ValueRef::Bytes(bytes) => Bencode::Bytes(bytes.to_vec()),
+            ValueRef::Number(number) => Bencode::Number(*number),
+            ValueRef::List(values) => Bencode::List(values.iter().map(Into::into).collect()),
+            ValueRef::Dictionary(entries) => Bencode::Dictionary(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_vec(), v.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Parses the value at the start of `input` leniently, but with the location of any error.
+pub(super) fn parse(input: &[u8]) -> Result<BencodeRef<'_>, ParseError> {
+    Parser::new(input, false).value()
+}
+
+/// Collects every strict-mode violation in `input`. Violations that leave the input readable,
+/// such as unsorted keys, don't stop validation; the first structural error does.
+pub fn validate(input: &[u8]) -> Vec<ParseError> {
+    let mut parser = Parser::new(input, true);
+    if let Err(e) = parser.document() {
+        parser.violations.push(e);
+    }
+    parser.violations
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    path: Vec<PathSegment>,
+    max_depth: usize,
+    strict: bool,
+    violations: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8], strict: bool) -> Self {
+        Self {
+            input,
+            pos: 0,
+            path: Vec::new(),
+            max_depth: MAX_DEPTH,
+            strict,
+            violations: Vec::new(),
+        }
+    }
+
+    /// Parses a value that must span the whole input.
+    fn document(&mut self) -> Result<BencodeRef<'a>, ParseError> {
+        let value = self.value()?;
+        if self.pos < self.input.len() {
+            self.violation(BencodeError::TrailingData, self.pos);
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<BencodeRef<'a>, ParseError> {
+        let start = self.pos;
+
+        let value = match self.peek() {
+            Some(b'l' | b'd') if self.path.len() == self.max_depth => {
+                return Err(self.error(BencodeError::DepthLimit, start))
+            }
+            Some(b'0'..=b'9') => ValueRef::Bytes(self.bytes()?),
+            Some(b'i') => {
+                self.pos += 1;
+                let digits = self.until(b'e', start)?;
+                let number = parse_number(digits).map_err(|kind| self.error(kind, start))?;
+                match digits {
+                    [b'-', b'0', ..] if digits.len() == 2 => {
+                        self.violation(BencodeError::NegativeZero, start)
+                    }
+                    [b'0', _, ..] | [b'-', b'0', ..] => {
+                        self.violation(BencodeError::LeadingZero, start)
+                    }
+                    [b'+', ..] => self.violation(BencodeError::InvalidNumber, start),
+                    _ => {}
+                }
+                ValueRef::Number(number)
+            }
+            Some(b'l') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+
+                loop {
+                    match self.peek() {
+                        Some(b'e') => break,
+                        None => return Err(self.error(BencodeError::MissingDelimeter, self.pos)),
+                        _ => {
+                            self.path.push(PathSegment::Index(values.len()));
+                            values.push(self.value()?);
+                            self.path.pop();
+                        }
+                    }
+                }
+
+                self.pos += 1;
+                ValueRef::List(values)
+            }
+            Some(b'd') => {
+                self.pos += 1;
+                let mut entries: Vec<(&'a [u8], BencodeRef<'a>)> = Vec::new();
+
+                loop {
+                    match self.peek() {
+                        Some(b'e') => break,
+                        None => return Err(self.error(BencodeError::MissingDelimeter, self.pos)),
+                        Some(b'0'..=b'9') => {
+                            let key_start = self.pos;
+                            let key = self.bytes()?;
+                            self.path.push(PathSegment::Key(key.to_vec()));
+                            if let Some((previous, _)) = entries.last() {
+                                if key == *previous {
+                                    self.violation(BencodeError::DuplicateKey, key_start);
+                                } else if key < *previous {
+                                    self.violation(BencodeError::UnsortedKeys, key_start);
+                                }
+                            }
+                            let value = self.value()?;
+                            self.path.pop();
+                            entries.push((key, value));
+                        }
+                        Some(_) => return Err(self.error(BencodeError::InvalidKey, self.pos)),
+                    }
+                }
+
+                self.pos += 1;
+                ValueRef::Dictionary(entries)
+            }
+            Some(c) => return Err(self.error(BencodeError::UnexpectedByte(c), start)),
+            None => return Err(self.error(BencodeError::EmptyInput, start)),
+        };
+
+        Ok(BencodeRef {
+            raw: &self.input[start..self.pos],
+            span: start..self.pos,
+            value,
+        })
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], ParseError> {
+        let start = self.pos;
+        let digits = self.until(b':', start)?;
+        let length = parse_number(digits).map_err(|kind| self.error(kind, start))?;
+        if digits.len() > 1 && digits[0] == b'0' {
+            self.violation(BencodeError::LeadingZero, start);
+        }
+        let bytes = self
+            .input
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..length))
+            .ok_or_else(|| self.error(BencodeError::InvalidLength, start))?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    /// Returns everything up to `delimeter` and moves past it.
+    fn until(&mut self, delimeter: u8, start: usize) -> Result<&'a [u8], ParseError> {
+        let rest = &self.input[self.pos..];
+        let end = rest
+            .iter()
+            .position(|&b| b == delimeter)
+            .ok_or_else(|| self.error(BencodeError::MissingDelimeter, start))?;
+        self.pos += end + 1;
+        Ok(&rest[..end])
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn error(&self, kind: BencodeError, offset: usize) -> ParseError {
+        ParseError {
+            kind,
+            offset,
+            path: Path(self.path.clone()),
+        }
+    }
+
+    /// Records a deviation from canonical bencode. Only strict mode cares about these.
+    fn violation(&mut self, kind: BencodeError, offset: usize) {
+        if self.strict {
+            let error = self.error(kind, offset);
+            self.violations.push(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_spans() {
+        let input = b"d8:announce3:url4:infod6:lengthi5e4:name1:aee";
+        let value = BencodeRef::new(input).unwrap();
+        assert_eq!(value.span(), 0..input.len());
+
+        let info = value.get("info").unwrap();
+        assert_eq!(info.span(), 22..input.len() - 1);
+        assert_eq!(info.raw(), b"d6:lengthi5e4:name1:ae");
+        assert_eq!(info.get("length").unwrap().as_number(), Some(5));
+        assert_eq!(info.get("name").unwrap().raw(), b"1:a");
+        assert_eq!(value.get("announce").unwrap().as_bytes(), Some(&b"url"[..]));
+    }
+
+    #[test]
+    fn borrows_from_input() {
+        let input = b"l4:\xde\xad\xbe\xefi-3ee".to_vec();
+        let value = BencodeRef::new(&input).unwrap();
+        let list = value.as_list().unwrap();
+        let bytes = list[0].as_bytes().unwrap();
+        assert_eq!(bytes.as_ptr(), input[3..].as_ptr());
+        assert_eq!(list[1].as_number(), Some(-3));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(BencodeRef::new(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            BencodeRef::new(&nested(MAX_DEPTH + 1)),
+            Err(BencodeError::DepthLimit)
+        );
+        assert_eq!(
+            BencodeRef::strict(&vec![b'l'; 1 << 20]).unwrap_err().kind,
+            BencodeError::DepthLimit
+        );
+    }
+
+    #[test]
+    fn ignores_trailing_data() {
+        let value = BencodeRef::new(b"i1ei2e").unwrap();
+        assert_eq!(value.span(), 0..3);
+    }
+
+    #[test]
+    fn strict_rejects_non_canonical_input() {
+        let kind = |input: &[u8]| BencodeRef::strict(input).map(|_| ()).map_err(|e| e.kind);
+        assert_eq!(kind(b"i03e"), Err(BencodeError::LeadingZero));
+        assert_eq!(kind(b"i-03e"), Err(BencodeError::LeadingZero));
+        assert_eq!(kind(b"i-0e"), Err(BencodeError::NegativeZero));
+        assert_eq!(kind(b"i+3e"), Err(BencodeError::InvalidNumber));
+        assert_eq!(kind(b"03:abc"), Err(BencodeError::LeadingZero));
+        assert_eq!(kind(b"d1:bi1e1:ai2ee"), Err(BencodeError::UnsortedKeys));
+        assert_eq!(kind(b"d1:ai1e1:ai2ee"), Err(BencodeError::DuplicateKey));
+        assert_eq!(kind(b"i1ei2e"), Err(BencodeError::TrailingData));
+        assert_eq!(kind(b"x"), Err(BencodeError::UnexpectedByte(b'x')));
+        assert_eq!(kind(b"i0e"), Ok(()));
+        assert_eq!(kind(b"0:"), Ok(()));
+        assert_eq!(kind(b"d1:ai-1e1:bi10ee"), Ok(()));
+
+        // The lenient parser accepts all of these.
+        assert!(BencodeRef::new(b"d1:bi03e1:ai-0ee").is_ok());
+    }
+
+    #[test]
+    fn reports_offset_and_path() {
+        let input = b"d4:infod5:filesld6:lengthi1eed6:lengthi02eeeee";
+        let error = BencodeRef::strict(input).unwrap_err();
+        assert_eq!(error.kind, BencodeError::LeadingZero);
+        assert_eq!(error.offset, 38);
+        assert_eq!(error.path.to_string(), "info.files[1].length");
+
+        let error = BencodeRef::strict(b"l1:ad1:bi1e").unwrap_err();
+        assert_eq!(error.kind, BencodeError::MissingDelimeter);
+        assert_eq!(error.offset, 11);
+        assert_eq!(error.path.to_string(), "[1]");
+    }
+
+    #[test]
+    fn validates_every_violation() {
+        let violations = validate(b"d1:bi01e1:ai-0ee");
+        let violations: Vec<_> = violations
+            .iter()
+            .map(|e| (e.kind.clone(), e.path.to_string()))
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                (BencodeError::LeadingZero, "b".to_string()),
+                (BencodeError::UnsortedKeys, "a".to_string()),
+                (BencodeError::NegativeZero, "a".to_string()),
+            ]
+        );
+    }
+}
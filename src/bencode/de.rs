@@ -0,0 +1,442 @@
+use std::collections::BTreeMap;
+
+use serde::de::{self, DeserializeSeed, Visitor};
+use serde::forward_to_deserialize_any;
+use serde_bytes::ByteBuf;
+
+use super::{borrowed, Bencode, BencodeError, BencodeRef, ParseError, Path, PathSegment, ValueRef};
+
+/// Deserializes a `T` from the bencode value at the start of `input`. Byte strings are borrowed
+/// from `input` where the target type allows it.
+pub fn from_bytes<'de, T: de::Deserialize<'de>>(input: &'de [u8]) -> Result<T, ParseError> {
+    let value = borrowed::parse(input)?;
+    T::deserialize(Deserializer::new(&value, &mut Vec::new()))
+}
+
+impl de::Error for ParseError {
+    /// The location is filled in by the [`Deserializer`] the error bubbles up through.
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self {
+            kind: BencodeError::Custom(msg.to_string()),
+            offset: 0,
+            path: Path::default(),
+        }
+    }
+}
+
+/// Deserializes from an already parsed [`BencodeRef`], keeping track of the path so errors point
+/// at the offending value.
+pub struct Deserializer<'a, 'de> {
+    value: &'a BencodeRef<'de>,
+    path: &'a mut Vec<PathSegment>,
+}
+
+impl<'a, 'de> Deserializer<'a, 'de> {
+    pub fn new(value: &'a BencodeRef<'de>, path: &'a mut Vec<PathSegment>) -> Self {
+        Self { value, path }
+    }
+
+    /// Errors that don't know where they happened yet are attributed to this value. Errors from
+    /// deeper values have already been located and are left alone.
+    fn locate<T>(&self, result: Result<T, ParseError>) -> Result<T, ParseError> {
+        result.map_err(|mut e| {
+            if e.offset == 0 && e.path.is_empty() {
+                e.offset = self.value.span().start;
+                e.path = Path(self.path.clone());
+            }
+            e
+        })
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_, 'de> {
+    type Error = ParseError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let result = match self.value.value() {
+            ValueRef::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            ValueRef::Number(number) => visitor.visit_i64(*number as i64),
+            ValueRef::List(values) => visitor.visit_seq(ListAccess {
+                values: values.iter().enumerate(),
+                path: self.path,
+            }),
+            ValueRef::Dictionary(entries) => visitor.visit_map(DictionaryAccess {
+                entries: entries.iter(),
+                value: None,
+                path: self.path,
+            }),
+        };
+        self.locate(result)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let result = match self.value.value() {
+            ValueRef::Number(0) => visitor.visit_bool(false),
+            ValueRef::Number(1) => visitor.visit_bool(true),
+            _ => return self.deserialize_any(visitor),
+        };
+        self.locate(result)
+    }
+
+    /// Strings are byte strings in bencode, so they are only handed out as `str` when they are
+    /// valid UTF-8.
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let result = match self.value.value() {
+            ValueRef::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => visitor.visit_borrowed_str(string),
+                Err(_) => visitor.visit_borrowed_bytes(bytes),
+            },
+            _ => return self.deserialize_any(visitor),
+        };
+        self.locate(result)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    /// Values that are present are never `None`, missing dictionary keys are.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let result = match self.value.value() {
+            ValueRef::Bytes(_) => visitor.visit_enum(VariantAccess {
+                variant: self.value,
+                value: None,
+                path: self.path,
+            }),
+            ValueRef::Dictionary(entries) if entries.len() == 1 => {
+                let (variant, value) = &entries[0];
+                self.path.push(PathSegment::Key(variant.to_vec()));
+                let result = visitor.visit_enum(VariantAccess {
+                    variant: self.value,
+                    value: Some(value),
+                    path: self.path,
+                });
+                self.path.pop();
+                result
+            }
+            _ => Err(de::Error::custom(
+                "expected a string or a dictionary with a single key for an enum",
+            )),
+        };
+        self.locate(result)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct seq
+        tuple tuple_struct map struct
+    }
+}
+
+struct ListAccess<'a, I> {
+    values: I,
+    path: &'a mut Vec<PathSegment>,
+}
+
+impl<'a, 'de, I> de::SeqAccess<'de> for ListAccess<'_, I>
+where
+    I: Iterator<Item = (usize, &'a BencodeRef<'de>)>,
+    'de: 'a,
+{
+    type Error = ParseError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        let Some((index, value)) = self.values.next() else {
+            return Ok(None);
+        };
+        self.path.push(PathSegment::Index(index));
+        let result = seed.deserialize(Deserializer::new(value, self.path));
+        self.path.pop();
+        result.map(Some)
+    }
+}
+
+struct DictionaryAccess<'a, 'de, I> {
+    entries: I,
+    value: Option<(&'de [u8], &'a BencodeRef<'de>)>,
+    path: &'a mut Vec<PathSegment>,
+}
+
+impl<'a, 'de, I> de::MapAccess<'de> for DictionaryAccess<'a, 'de, I>
+where
+    I: Iterator<Item = &'a (&'de [u8], BencodeRef<'de>)>,
+    'de: 'a,
+{
+    type Error = ParseError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some((key, value));
+        seed.deserialize(KeyDeserializer(key)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, value) = self
+            .value
+            .take()
+            .expect("next_key_seed is called before next_value_seed");
+        self.path.push(PathSegment::Key(key.to_vec()));
+        let result = seed.deserialize(Deserializer::new(value, self.path));
+        self.path.pop();
+        result
+    }
+}
+
+/// Dictionary keys, which are always byte strings.
+struct KeyDeserializer<'de>(&'de [u8]);
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer<'de> {
+    type Error = ParseError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match std::str::from_utf8(self.0) {
+            Ok(string) => visitor.visit_borrowed_str(string),
+            Err(_) => visitor.visit_borrowed_bytes(self.0),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string unit unit_struct
+        option newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct VariantAccess<'a, 'de> {
+    variant: &'a BencodeRef<'de>,
+    value: Option<&'a BencodeRef<'de>>,
+    path: &'a mut Vec<PathSegment>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for VariantAccess<'a, 'de> {
+    type Error = ParseError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let name = match (self.variant.value(), self.value) {
+            (ValueRef::Bytes(name), None) => *name,
+            (ValueRef::Dictionary(entries), Some(_)) => entries[0].0,
+            _ => unreachable!(
+                "variant access is only created for strings and single-key dictionaries"
+            ),
+        };
+        let variant = seed.deserialize(KeyDeserializer(name))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'_, 'de> {
+    type Error = ParseError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        match self.value {
+            None => Ok(()),
+            Some(_) => Err(de::Error::custom("expected a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        match self.value {
+            Some(value) => seed.deserialize(Deserializer::new(value, self.path)),
+            None => Err(de::Error::custom("expected a newtype variant")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Some(value) => {
+                de::Deserializer::deserialize_any(Deserializer::new(value, self.path), visitor)
+            }
+            None => Err(de::Error::custom("expected a tuple variant")),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.value {
+            Some(value) => {
+                de::Deserializer::deserialize_any(Deserializer::new(value, self.path), visitor)
+            }
+            None => Err(de::Error::custom("expected a struct variant")),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Bencode {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BencodeVisitor)
+    }
+}
+
+struct BencodeVisitor;
+
+impl<'de> Visitor<'de> for BencodeVisitor {
+    type Value = Bencode;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a bencode value")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        v.try_into()
+            .map(Bencode::Number)
+            .map_err(|_| E::custom(format!("{v} is out of range")))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        v.try_into()
+            .map(Bencode::Number)
+            .map_err(|_| E::custom(format!("{v} is out of range")))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Bencode::List(values))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut dictionary = BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<ByteBuf, _>()? {
+            dictionary.insert(key.into_vec(), value);
+        }
+        Ok(Bencode::Dictionary(dictionary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::bencode::to_bytes;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct File<'a> {
+        name: String,
+        length: usize,
+        #[serde(with = "serde_bytes")]
+        hash: &'a [u8],
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        tags: Vec<Tag>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Tag {
+        Plain,
+        Weighted(u8),
+    }
+
+    #[test]
+    fn round_trips_structs() {
+        let encoded = b"d4:hash2:\xff\x006:lengthi5e4:name3:foo4:tagsl5:Plaind8:Weightedi3eeee";
+        let file: File = from_bytes(encoded).unwrap();
+        assert_eq!(
+            file,
+            File {
+                name: "foo".to_string(),
+                length: 5,
+                hash: &[0xff, 0x00],
+                comment: None,
+                tags: vec![Tag::Plain, Tag::Weighted(3)],
+            }
+        );
+        assert_eq!(file.hash.as_ptr(), encoded[9..].as_ptr());
+
+        // Keys come out sorted even though the struct declares them in another order.
+        assert_eq!(to_bytes(&file).unwrap(), encoded);
+    }
+
+    #[test]
+    fn serializes_maps_with_sorted_keys() {
+        let map = BTreeMap::from([("b", 1), ("a", 2)]);
+        assert_eq!(to_bytes(&map).unwrap(), b"d1:ai2e1:bi1ee");
+        assert_eq!(
+            to_bytes(&1.5f64),
+            Err(BencodeError::Unsupported("floating point number"))
+        );
+    }
+
+    #[test]
+    fn locates_errors() {
+        let error = from_bytes::<File>(b"d4:hash0:4:name3:foo6:length3:bar4:tagsleee").unwrap_err();
+        assert_eq!(error.offset, 28);
+        assert_eq!(error.path.to_string(), "length");
+
+        let error = from_bytes::<File>(b"d4:hash0:4:name3:foo6:lengthi1e4:tagsl1:xee").unwrap_err();
+        assert_eq!(error.path.to_string(), "tags[0]");
+
+        let error = from_bytes::<File>(b"d4:name3:fooe").unwrap_err();
+        assert_eq!(
+            error.kind,
+            BencodeError::Custom("missing field `length`".to_string())
+        );
+        assert_eq!(error.offset, 0);
+    }
+}
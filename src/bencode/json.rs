@@ -0,0 +1,128 @@
+//! Lossless mapping between bencode and JSON.
+//!
+//! Byte strings holding valid UTF-8 become JSON strings and any other byte string (such as the
+//! SHA-1 hashes in `pieces`) becomes `{"$hex": "<hex>"}`. Dictionary keys that aren't UTF-8, or
+//! that start with `$hex` themselves, are written as `"$hex:<hex>"`, so no bencode dictionary can
+//! be mistaken for a byte string on the way back.
+
+use serde_json::{Map, Number, Value};
+
+use super::{Bencode, BencodeError};
+
+const HEX: &str = "$hex";
+const HEX_KEY: &str = "$hex:";
+
+impl From<&Bencode> for Value {
+    fn from(value: &Bencode) -> Self {
+        match value {
+            Bencode::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(string) => Value::String(string.to_owned()),
+                Err(_) => Value::Object(Map::from_iter([(
+                    HEX.to_string(),
+                    Value::String(hex::encode(bytes)),
+                )])),
+            },
+            Bencode::Number(number) => Value::Number((*number).into()),
+            Bencode::List(values) => Value::Array(values.iter().map(Into::into).collect()),
+            Bencode::Dictionary(dictionary) => Value::Object(
+                dictionary
+                    .iter()
+                    .map(|(k, v)| (render_key(k), v.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl TryFrom<&Value> for Bencode {
+    type Error = BencodeError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(string) => Ok(string.as_str().into()),
+            Value::Number(number) => parse_number(number).map(Bencode::Number),
+            Value::Array(values) => values
+                .iter()
+                .map(Bencode::try_from)
+                .collect::<Result<_, _>>()
+                .map(Bencode::List),
+            Value::Object(object) => match object.get(HEX) {
+                Some(Value::String(encoded)) if object.len() == 1 => {
+                    decode_hex(encoded).map(Bencode::Bytes)
+                }
+                _ => object
+                    .iter()
+                    .map(|(k, v)| Ok((parse_key(k)?, v.try_into()?)))
+                    .collect::<Result<_, _>>()
+                    .map(Bencode::Dictionary),
+            },
+            Value::Bool(_) => Err(BencodeError::Unsupported("JSON boolean")),
+            Value::Null => Err(BencodeError::Unsupported("JSON null")),
+        }
+    }
+}
+
+fn render_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if !key.starts_with(HEX) => key.to_owned(),
+        _ => format!("{HEX_KEY}{}", hex::encode(key)),
+    }
+}
+
+fn parse_key(key: &str) -> Result<Vec<u8>, BencodeError> {
+    match key.strip_prefix(HEX_KEY) {
+        Some(encoded) => decode_hex(encoded),
+        None => Ok(key.as_bytes().to_vec()),
+    }
+}
+
+fn decode_hex(encoded: &str) -> Result<Vec<u8>, BencodeError> {
+    hex::decode(encoded).map_err(|e| BencodeError::Custom(format!("invalid {HEX} value: {e}")))
+}
+
+fn parse_number(number: &Number) -> Result<isize, BencodeError> {
+    number
+        .as_i64()
+        .and_then(|number| number.try_into().ok())
+        .ok_or(BencodeError::InvalidNumber)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_through_json() {
+        let original = Bencode::new(
+            b"d4:$hex1:x8:announce3:url4:infod6:lengthi-5e6:pieces2:\xff\x00e2:\xfe\xfeli1eee",
+        )
+        .unwrap();
+
+        let value = Value::from(&original);
+        assert_eq!(
+            value,
+            json!({
+                "$hex:24686578": "x",
+                "announce": "url",
+                "info": {"length": -5, "pieces": {"$hex": "ff00"}},
+                "$hex:fefe": [1],
+            })
+        );
+        assert_eq!(Bencode::try_from(&value), Ok(original));
+    }
+
+    #[test]
+    fn rejects_values_bencode_cannot_hold() {
+        assert_eq!(
+            Bencode::try_from(&json!([1.5])),
+            Err(BencodeError::InvalidNumber)
+        );
+        assert_eq!(
+            Bencode::try_from(&json!({"a": null})),
+            Err(BencodeError::Unsupported("JSON null"))
+        );
+        assert!(Bencode::try_from(&json!({"$hex": "zz"})).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::Bencode;
+
+/// A path selecting values inside a [`Bencode`] tree, such as `info.files[*].path` or
+/// `announce-list[0][0]`.
+///
+/// Dictionary keys are separated by `.`; keys containing `.` or `[` can be written as
+/// `["name.utf-8"]`. `[n]` indexes into a list, counting from the end when negative, and `*` or
+/// `[*]` selects every value of a list or dictionary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query(Vec<Selector>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selector {
+    Key(Vec<u8>),
+    Index(isize),
+    Wildcard,
+}
+
+impl Query {
+    /// Returns every value the query matches, in document order.
+    pub fn select<'a>(&self, value: &'a Bencode) -> Vec<&'a Bencode> {
+        let mut matches = vec![value];
+
+        for selector in &self.0 {
+            matches = matches
+                .into_iter()
+                .flat_map(|value| selector.select(value))
+                .collect();
+        }
+
+        matches
+    }
+}
+
+impl Selector {
+    fn select<'a>(&self, value: &'a Bencode) -> Vec<&'a Bencode> {
+        match (self, value) {
+            (Selector::Key(key), Bencode::Dictionary(dictionary)) => {
+                dictionary.get(key).into_iter().collect()
+            }
+            (Selector::Index(index), Bencode::List(values)) => {
+                let index = if *index < 0 {
+                    values.len().checked_sub(index.unsigned_abs())
+                } else {
+                    Some(*index as usize)
+                };
+                index.and_then(|i| values.get(i)).into_iter().collect()
+            }
+            (Selector::Wildcard, Bencode::List(values)) => values.iter().collect(),
+            (Selector::Wildcard, Bencode::Dictionary(dictionary)) => dictionary.values().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Bencode {
+    /// Shorthand for parsing `query` and selecting it from this value.
+    pub fn query(&self, query: &str) -> Result<Vec<&Bencode>, QueryError> {
+        Ok(query.parse::<Query>()?.select(self))
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut selectors = Vec::new();
+        let mut rest = s;
+
+        while let Some(c) = rest.chars().next() {
+            let position = s.len() - rest.len();
+
+            if c == '[' {
+                let end = if rest.starts_with("[\"") {
+                    closing_quote(rest).ok_or(QueryError::Unterminated(position))?
+                } else {
+                    rest.find(']').ok_or(QueryError::Unterminated(position))?
+                };
+                let inner = &rest[1..end];
+                selectors.push(match inner {
+                    "*" => Selector::Wildcard,
+                    _ if inner.starts_with('"') => Selector::Key(unquote(inner).into_bytes()),
+                    _ => Selector::Index(
+                        inner
+                            .parse()
+                            .map_err(|_| QueryError::InvalidIndex(inner.to_string()))?,
+                    ),
+                });
+                rest = &rest[end + 1..];
+                continue;
+            }
+
+            // Keys after the first one are introduced by a dot.
+            let key = if selectors.is_empty() {
+                rest
+            } else {
+                rest.strip_prefix('.')
+                    .ok_or(QueryError::UnexpectedChar(c, position))?
+            };
+            let end = key.find(['.', '[']).unwrap_or(key.len());
+            if end == 0 {
+                return Err(QueryError::EmptyKey(s.len() - key.len()));
+            }
+            selectors.push(match &key[..end] {
+                "*" => Selector::Wildcard,
+                key => Selector::Key(key.as_bytes().to_vec()),
+            });
+            rest = &key[end..];
+        }
+
+        Ok(Query(selectors))
+    }
+}
+
+/// Position of the `]` that closes a `["quoted key"]` at the start of `s`.
+fn closing_quote(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(2) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return s[i + 1..].starts_with(']').then_some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(quoted: &str) -> String {
+    let mut unquoted = String::with_capacity(quoted.len());
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, selector) in self.0.iter().enumerate() {
+            match selector {
+                Selector::Key(key) => match std::str::from_utf8(key) {
+                    Ok(key) if !key.contains(['.', '[']) && key != "*" => {
+                        if i > 0 {
+                            f.write_str(".")?;
+                        }
+                        f.write_str(key)?;
+                    }
+                    _ => {
+                        let key = String::from_utf8_lossy(key);
+                        write!(
+                            f,
+                            "[\"{}\"]",
+                            key.replace('\\', "\\\\").replace('"', "\\\"")
+                        )?;
+                    }
+                },
+                Selector::Index(index) => write!(f, "[{index}]")?,
+                Selector::Wildcard => f.write_str("[*]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum QueryError {
+    #[error("unexpected {0:?} at position {1}")]
+    UnexpectedChar(char, usize),
+    #[error("empty key at position {0}")]
+    EmptyKey(usize),
+    #[error("unterminated bracket at position {0}")]
+    Unterminated(usize),
+    #[error("invalid index {0:?}")]
+    InvalidIndex(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent() -> Bencode {
+        Bencode::new(
+            b"d13:announce-listll3:onee\
+              l3:two5:threeee\
+              4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl1:b1:ceee\
+              10:name.utf-81:xee",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn selects_values() {
+        let torrent = torrent();
+        let query = |q| torrent.query(q).unwrap();
+
+        assert_eq!(query("announce-list[0][0]"), [&Bencode::from("one")]);
+        assert_eq!(query("announce-list[-1][-1]"), [&Bencode::from("three")]);
+        assert_eq!(
+            query("info.files[*].length"),
+            [&Bencode::Number(1), &Bencode::Number(2)]
+        );
+        assert_eq!(
+            query("info.files.*.path[*]"),
+            [
+                &Bencode::from("a"),
+                &Bencode::from("b"),
+                &Bencode::from("c")
+            ]
+        );
+        assert_eq!(query("info[\"name.utf-8\"]"), [&Bencode::from("x")]);
+        assert_eq!(query(""), [&torrent]);
+        assert!(query("info.missing").is_empty());
+        assert!(query("announce-list[5]").is_empty());
+    }
+
+    #[test]
+    fn parses_and_displays_queries() {
+        for query in ["info.files[*].path", "a[0][-2]", "info[\"name.utf-8\"]"] {
+            assert_eq!(query.parse::<Query>().unwrap().to_string(), query);
+        }
+
+        assert_eq!(
+            "info..length".parse::<Query>(),
+            Err(QueryError::EmptyKey(5))
+        );
+        assert_eq!("info[0".parse::<Query>(), Err(QueryError::Unterminated(4)));
+        assert_eq!(
+            "info[x]".parse::<Query>(),
+            Err(QueryError::InvalidIndex("x".to_string()))
+        );
+        assert_eq!(
+            "a[0]b".parse::<Query>(),
+            Err(QueryError::UnexpectedChar('b', 4))
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::Decoder;
+
+use super::{parse_number, Bencode, BencodeError, BencodeRef, ParseError, Path, MAX_DEPTH};
+
+const DEFAULT_MAX_SIZE: usize = 16 << 20;
+
+/// Longest integer bencode can hold in an `isize`, sign included.
+const MAX_DIGITS: usize = 20;
+
+/// Incremental decoder for a stream of bencode values that may arrive in arbitrary chunks.
+///
+/// `decode` returns `Ok(None)` while the value at the front of the buffer is incomplete and only
+/// fails once the bytes received so far can't be the start of a valid value. Input is scanned
+/// only once no matter how many chunks it is split into.
+#[derive(Debug)]
+pub struct BencodeDecoder {
+    max_depth: usize,
+    max_size: usize,
+    /// How far into the buffer the current value has been scanned.
+    pos: usize,
+    /// Containers that are still open at `pos`.
+    stack: Vec<Container>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Container {
+    List,
+    Dictionary { expect_key: bool },
+}
+
+impl BencodeDecoder {
+    pub fn new() -> Self {
+        Self {
+            max_depth: MAX_DEPTH,
+            max_size: DEFAULT_MAX_SIZE,
+            pos: 0,
+            stack: Vec::new(),
+        }
+    }
+
+    /// Maximum nesting of lists and dictionaries.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Maximum encoded size in bytes of a single value.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Scans forward from where the last call stopped. Returns the length of the value at the
+    /// front of `src` once it is complete.
+    fn scan(&mut self, src: &[u8]) -> Result<Option<usize>, ParseError> {
+        while let Some(&byte) = src.get(self.pos) {
+            let start = self.pos;
+
+            if let Some(Container::Dictionary { expect_key: true }) = self.stack.last() {
+                if !matches!(byte, b'0'..=b'9' | b'e') {
+                    return Err(self.error(BencodeError::InvalidKey, start));
+                }
+            }
+
+            match byte {
+                b'e' => match self.stack.pop() {
+                    Some(Container::List | Container::Dictionary { expect_key: true }) => {
+                        self.pos += 1;
+                    }
+                    _ => return Err(self.error(BencodeError::UnexpectedByte(byte), start)),
+                },
+                b'i' => {
+                    let Some(digits) = self.delimited(src, start + 1, b'e')? else {
+                        return Ok(None);
+                    };
+                    parse_number::<isize>(digits).map_err(|kind| self.error(kind, start))?;
+                    self.pos += 1 + digits.len() + 1;
+                }
+                b'0'..=b'9' => {
+                    let Some(digits) = self.delimited(src, start, b':')? else {
+                        return Ok(None);
+                    };
+                    let length: usize =
+                        parse_number(digits).map_err(|kind| self.error(kind, start))?;
+                    // Hostile lengths would overflow the addition.
+                    let end = (start + digits.len() + 1)
+                        .checked_add(length)
+                        .filter(|&end| end <= self.max_size)
+                        .ok_or_else(|| self.error(BencodeError::SizeLimit, start))?;
+                    if end > src.len() {
+                        return Ok(None);
+                    }
+                    self.pos = end;
+                }
+                b'l' | b'd' => {
+                    if self.stack.len() == self.max_depth {
+                        return Err(self.error(BencodeError::DepthLimit, start));
+                    }
+                    self.stack.push(match byte {
+                        b'l' => Container::List,
+                        _ => Container::Dictionary { expect_key: true },
+                    });
+                    self.pos += 1;
+                }
+                _ => return Err(self.error(BencodeError::UnexpectedByte(byte), start)),
+            }
+
+            if self.pos > self.max_size {
+                return Err(self.error(BencodeError::SizeLimit, start));
+            }
+
+            // A container that was just opened holds no value yet.
+            if matches!(byte, b'l' | b'd') {
+                continue;
+            }
+            match self.stack.last_mut() {
+                None => return Ok(Some(self.pos)),
+                Some(Container::Dictionary { expect_key }) => *expect_key = !*expect_key,
+                Some(Container::List) => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The bytes from `from` up to `delimeter`, or `None` if the delimeter hasn't arrived yet and
+    /// what is there so far could still be a valid number.
+    fn delimited<'a>(
+        &self,
+        src: &'a [u8],
+        from: usize,
+        delimeter: u8,
+    ) -> Result<Option<&'a [u8]>, ParseError> {
+        let rest = &src[from.min(src.len())..];
+        match rest
+            .iter()
+            .take(MAX_DIGITS + 1)
+            .position(|&b| b == delimeter)
+        {
+            Some(end) => Ok(Some(&rest[..end])),
+            None if rest.len() > MAX_DIGITS
+                || rest.iter().any(|b| !matches!(b, b'-' | b'0'..=b'9')) =>
+            {
+                Err(self.error(BencodeError::InvalidNumber, self.pos))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn error(&self, kind: BencodeError, offset: usize) -> ParseError {
+        ParseError {
+            kind,
+            offset,
+            path: Path::default(),
+        }
+    }
+}
+
+impl Default for BencodeDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Bencode(#[from] ParseError),
+}
+
+impl Decoder for BencodeDecoder {
+    type Item = Bencode;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(length) = self.scan(src)? else {
+            return Ok(None);
+        };
+
+        let value = BencodeRef::with_max_depth(&src[..length], self.max_depth)
+            .map(|value| Bencode::from(&value))
+            .map_err(|kind| self.error(kind, 0))?;
+
+        src.advance(length);
+        self.pos = 0;
+
+        Ok(Some(value))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(value) => Ok(Some(value)),
+            None if src.is_empty() => Ok(None),
+            None => Err(self.error(BencodeError::Truncated, src.len()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(decoder: &mut BencodeDecoder, chunks: &[&[u8]]) -> Vec<Result<Bencode, BencodeError>> {
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk);
+            loop {
+                match decoder.decode(&mut buffer) {
+                    Ok(Some(value)) => decoded.push(Ok(value)),
+                    Ok(None) => break,
+                    Err(DecodeError::Bencode(e)) => {
+                        decoded.push(Err(e.kind));
+                        return decoded;
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+        decoded
+    }
+
+    #[test]
+    fn decodes_across_chunks() {
+        let input = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1ei42e4:spam";
+        let chunks: Vec<&[u8]> = input.chunks(1).collect();
+        let decoded = feed(&mut BencodeDecoder::new(), &chunks);
+        assert_eq!(
+            decoded,
+            [
+                Ok([
+                    ("interval", Bencode::Number(1800)),
+                    (
+                        "peers",
+                        Bencode::Bytes(b"\x7f\x00\x00\x01\x1a\xe1".to_vec())
+                    ),
+                ]
+                .into_iter()
+                .collect()),
+                Ok(Bencode::Number(42)),
+                Ok(Bencode::from("spam")),
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_truncated_from_invalid() {
+        let mut decoder = BencodeDecoder::new();
+        assert!(feed(&mut decoder, &[b"l4:spa"]).is_empty());
+
+        let mut decoder = BencodeDecoder::new();
+        assert_eq!(
+            feed(&mut decoder, &[b"li1e", b"x"]),
+            [Err(BencodeError::UnexpectedByte(b'x'))]
+        );
+
+        let mut decoder = BencodeDecoder::new();
+        assert_eq!(
+            feed(&mut decoder, &[b"i12a"]),
+            [Err(BencodeError::InvalidNumber)]
+        );
+
+        let mut decoder = BencodeDecoder::new();
+        assert_eq!(
+            feed(&mut decoder, &[b"di1e"]),
+            [Err(BencodeError::InvalidKey)]
+        );
+
+        let mut decoder = BencodeDecoder::new();
+        let mut buffer = BytesMut::from(&b"l4:spa"[..]);
+        assert!(matches!(
+            decoder.decode_eof(&mut buffer),
+            Err(DecodeError::Bencode(ParseError {
+                kind: BencodeError::Truncated,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let mut decoder = BencodeDecoder::new().max_depth(2);
+        assert_eq!(
+            feed(&mut decoder, &[b"llle"]),
+            [Err(BencodeError::DepthLimit)]
+        );
+        let nested = [vec![b'l'; MAX_DEPTH + 1], vec![b'e'; MAX_DEPTH + 1]].concat();
+        let mut decoder = BencodeDecoder::new();
+        assert_eq!(
+            feed(&mut decoder, &[&nested]),
+            [Err(BencodeError::DepthLimit)]
+        );
+        let mut decoder = BencodeDecoder::new().max_depth(MAX_DEPTH + 1);
+        assert!(matches!(feed(&mut decoder, &[&nested])[..], [Ok(_)]));
+
+        // Declared string lengths are checked before the data arrives.
+        let mut decoder = BencodeDecoder::new().max_size(100);
+        assert_eq!(
+            feed(&mut decoder, &[b"99999:"]),
+            [Err(BencodeError::SizeLimit)]
+        );
+        let mut decoder = BencodeDecoder::new().max_size(usize::MAX);
+        assert_eq!(
+            feed(&mut decoder, &[b"l18446744073709551615:"]),
+            [Err(BencodeError::SizeLimit)]
+        );
+
+        let mut decoder = BencodeDecoder::new().max_size(8);
+        assert_eq!(
+            feed(&mut decoder, &[b"li1ei2e", b"i3e"]),
+            [Err(BencodeError::SizeLimit)]
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::bencode;
+
+/// Extended message id of the extension handshake. Every other id is assigned by the handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The extension handshake (BEP 10), exchanged in an extended message right after the bittorrent
+/// handshake by peers that both set the extension bit. Keys this crate doesn't know are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the id the sender wants to receive their messages with. An id of
+    /// 0 disables the extension.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+
+    /// Client name and version. Only informational, so it is kept as bytes rather than failing
+    /// the handshake when it isn't UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+
+    /// Number of outstanding requests the sender queues without dropping any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+
+    /// Size of the info dictionary, sent by peers that can serve it over `ut_metadata` (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+
+    /// Our address as the sender sees it, in 4 or 16 bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        bencode::from_bytes(data).context("parse extension handshake")
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        bencode::to_bytes(self).context("encode extension handshake")
+    }
+
+    /// The id the sender wants messages of extension `name` sent with, unless it doesn't speak it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != HANDSHAKE_ID)
+    }
+
+    /// The client name and version, with bytes that aren't UTF-8 replaced.
+    pub fn client(&self) -> Option<Cow<'_, str>> {
+        let v: &[u8] = self.v.as_deref()?;
+        Some(String::from_utf8_lossy(v))
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let ip: &[u8] = self.yourip.as_deref()?;
+        if let Ok(ip) = <[u8; 4]>::try_from(ip) {
+            Some(Ipv4Addr::from(ip).into())
+        } else if let Ok(ip) = <[u8; 16]>::try_from(ip) {
+            Some(Ipv6Addr::from(ip).into())
+        } else {
+            None
+        }
+    }
+}
+
+/// The extensions spoken on a connection.
+///
+/// Extensions plug in by registering their name before the bittorrent handshake. Their messages
+/// are then sent and received by name, with the ids of both ends looked up here.
+#[derive(Debug, Clone, Default)]
+pub struct Extensions {
+    local: BTreeMap<String, u8>,
+    metadata_size: Option<usize>,
+    remote: Option<ExtensionHandshake>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ids for the peer to send us messages with are handed out in registration order, from 1.
+    /// Fails once all 255 of them are taken.
+    pub fn register(mut self, name: impl Into<String>) -> anyhow::Result<Self> {
+        let name = name.into();
+        if !self.local.contains_key(&name) {
+            let id = u8::try_from(self.local.len() + 1).context("too many extensions")?;
+            self.local.insert(name, id);
+        }
+        Ok(self)
+    }
+
+    /// Advertises the size of the info dictionary we can serve.
+    pub fn metadata_size(mut self, metadata_size: usize) -> Self {
+        self.metadata_size = Some(metadata_size);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.local.is_empty()
+    }
+
+    /// The id the peer sends messages of extension `name` to us with.
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.local.get(name).copied()
+    }
+
+    /// The id messages of extension `name` must be sent to the peer with, once the peer's
+    /// handshake has arrived and if the peer speaks it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.as_ref()?.id(name)
+    }
+
+    /// The handshake the peer sent, if it has arrived.
+    pub fn remote(&self) -> Option<&ExtensionHandshake> {
+        self.remote.as_ref()
+    }
+
+    pub(crate) fn set_remote(&mut self, handshake: ExtensionHandshake) {
+        self.remote = Some(handshake);
+    }
+
+    /// The handshake we send.
+    pub fn handshake(&self) -> ExtensionHandshake {
+        ExtensionHandshake {
+            m: self
+                .local
+                .iter()
+                .map(|(name, &id)| (name.clone(), id.into()))
+                .collect(),
+            v: Some(ByteBuf::from(concat!(
+                env!("CARGO_PKG_NAME"),
+                " ",
+                env!("CARGO_PKG_VERSION")
+            ))),
+            reqq: None,
+            metadata_size: self.metadata_size,
+            yourip: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_handshake() {
+        let handshake = ExtensionHandshake::from_bytes(
+            b"d1:md11:LT_metadatai0e6:ut_pexi1e11:ut_metadatai3ee\
+              13:metadata_sizei31235e1:pi6881e4:reqqi250e\
+              1:v13:\xc2\xb5Torrent 1.26:yourip4:\x7f\x00\x00\x01e",
+        )
+        .unwrap();
+
+        assert_eq!(handshake.id("ut_metadata"), Some(3));
+        assert_eq!(handshake.id("ut_pex"), Some(1));
+        assert_eq!(handshake.id("LT_metadata"), None);
+        assert_eq!(handshake.id("ut_holepunch"), None);
+        assert_eq!(handshake.metadata_size, Some(31235));
+        assert_eq!(handshake.reqq, Some(250));
+        assert_eq!(handshake.client().as_deref(), Some("\u{b5}Torrent 1.2"));
+        assert_eq!(handshake.your_ip(), Some(Ipv4Addr::LOCALHOST.into()));
+
+        let handshake = ExtensionHandshake::from_bytes(&handshake.to_bytes().unwrap()).unwrap();
+        assert_eq!(handshake.id("ut_metadata"), Some(3));
+
+        let handshake = ExtensionHandshake::from_bytes(b"d1:md6:ut_pexi1ee1:v4:\xffTore").unwrap();
+        assert_eq!(handshake.id("ut_pex"), Some(1));
+        assert_eq!(handshake.client().as_deref(), Some("\u{fffd}Tor"));
+    }
+
+    #[test]
+    fn registers_extensions() {
+        let mut extensions = Extensions::new()
+            .register("ut_metadata")
+            .and_then(|extensions| extensions.register("ut_pex"))
+            .and_then(|extensions| extensions.register("ut_metadata"))
+            .unwrap()
+            .metadata_size(100);
+
+        assert_eq!(extensions.local_id("ut_metadata"), Some(1));
+        assert_eq!(extensions.local_id("ut_pex"), Some(2));
+        assert_eq!(extensions.remote_id("ut_metadata"), None);
+
+        let handshake = extensions.handshake();
+        assert_eq!(handshake.m.len(), 2);
+        assert_eq!(handshake.metadata_size, Some(100));
+
+        extensions.set_remote(ExtensionHandshake {
+            m: [("ut_metadata".to_string(), 7)].into_iter().collect(),
+            ..Default::default()
+        });
+        assert_eq!(extensions.remote_id("ut_metadata"), Some(7));
+        assert_eq!(extensions.remote_id("ut_pex"), None);
+    }
+
+    #[test]
+    fn runs_out_of_ids() {
+        let extensions = (0..255).try_fold(Extensions::new(), |extensions, i| {
+            extensions.register(format!("x{i}"))
+        });
+        let extensions = extensions.unwrap();
+        assert_eq!(extensions.local_id("x254"), Some(255));
+        assert!(extensions.register("one too many").is_err());
+    }
+}
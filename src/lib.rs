@@ -3,10 +3,17 @@ use std::ops::Deref;
 use sha1::{Digest, Sha1};
 
 pub mod bencode;
+pub mod extension;
+pub mod magnet;
 pub mod message;
 pub mod peer;
+pub mod resume;
+pub mod storage;
+#[cfg(test)]
+mod test_support;
 pub mod torrent;
 pub mod tracker;
+pub mod webseed;
 
 pub struct Hash([u8; 20]);
 
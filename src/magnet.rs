@@ -0,0 +1,378 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::{
+    bencode::{Bencode, BencodeRef},
+    extension::Extensions,
+    peer::{Id, Peer, Session},
+    torrent::{Info, Torrent},
+    tracker::{Peers, Tiers, TrackerRequest},
+    Hash,
+};
+
+const UT_METADATA: &str = "ut_metadata";
+const METADATA_PIECE: usize = 1 << 14;
+/// Largest info dictionary we accept from a peer.
+const METADATA_MAX: usize = 16 << 20;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// What we tell trackers is left to download before we know the size of the content. Some
+/// trackers don't hand out peers to clients that have nothing left.
+const UNKNOWN_LEFT: usize = 999;
+
+/// A magnet link (BEP 9), such as
+/// `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&x.pe=<peer>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    /// Peers from the trackers of the link, along with the ones it lists itself.
+    pub async fn peers(&self) -> anyhow::Result<Peers> {
+        let mut peers: Peers = self.peers.iter().copied().collect();
+
+        if !self.trackers.is_empty() {
+            let tiers: Vec<_> = self.trackers.iter().map(|url| vec![url.clone()]).collect();
+            let announced = Tiers::new("", Some(&tiers))
+                .announce(&self.info_hash, &TrackerRequest::new(UNKNOWN_LEFT))
+                .await;
+            match announced {
+                Ok(announced) => peers.merge(announced.peers),
+                Err(e) if !peers.is_empty() => warn!("{e:#}"),
+                Err(e) => return Err(e),
+            }
+        }
+
+        anyhow::ensure!(!peers.is_empty(), "magnet link has no trackers or peers");
+        Ok(peers)
+    }
+
+    /// Fetches the info dictionary from the first peer that sends one matching the info hash.
+    pub async fn torrent(&self) -> anyhow::Result<Torrent> {
+        for peer in self.peers().await?.iter() {
+            let addr = *peer.addr();
+            match tokio::time::timeout(PEER_TIMEOUT, self.torrent_from(addr)).await {
+                Ok(Ok(torrent)) => return Ok(torrent),
+                Ok(Err(e)) => warn!("peer {addr} failed: {e:#}"),
+                Err(_) => warn!("peer {addr} timed out"),
+            }
+        }
+
+        anyhow::bail!("no peer sent the metadata")
+    }
+
+    /// Fetches the info dictionary from a single peer.
+    pub async fn torrent_from(&self, addr: SocketAddr) -> anyhow::Result<Torrent> {
+        let mut peer = Peer::new(addr)
+            .with_extensions(Extensions::new().register(UT_METADATA)?)
+            .handshake(self.info_hash)
+            .await?;
+        let info = fetch_info(&mut peer, self.info_hash).await?;
+        Ok(Torrent::from_info(info, self.trackers.clone()))
+    }
+}
+
+/// Asks a peer for the info dictionary over the `ut_metadata` extension and checks it against
+/// `info_hash`.
+async fn fetch_info<P, T>(
+    peer: &mut Peer<Id, Session, P, T>,
+    info_hash: [u8; 20],
+) -> anyhow::Result<Info> {
+    let handshake = peer.extension_handshake().await?;
+    let size = handshake
+        .metadata_size
+        .context("peer didn't send the metadata size")?;
+    anyhow::ensure!(
+        size > 0 && size <= METADATA_MAX,
+        "metadata size {size} is out of range"
+    );
+
+    let mut metadata = Vec::with_capacity(size);
+    for piece in 0..size.div_ceil(METADATA_PIECE) {
+        let request: Bencode = [
+            ("msg_type", Bencode::from(0)),
+            ("piece", Bencode::from(piece as isize)),
+        ]
+        .into_iter()
+        .collect();
+        peer.send_extended(UT_METADATA, &request.encode()).await?;
+
+        let payload = peer.receive_extended(UT_METADATA).await?;
+        let header = BencodeRef::new(&payload).context("parse metadata message")?;
+        match header.get("msg_type").and_then(|t| t.as_number()) {
+            Some(1) => {}
+            Some(2) => anyhow::bail!("peer rejected metadata piece {piece}"),
+            _ => anyhow::bail!("unexpected metadata message"),
+        }
+        anyhow::ensure!(
+            header.get("piece").and_then(|p| p.as_number()) == Some(piece as isize),
+            "peer sent the wrong metadata piece"
+        );
+
+        let data = &payload[header.span().end..];
+        anyhow::ensure!(
+            data.len() == METADATA_PIECE.min(size - metadata.len()),
+            "metadata piece {piece} has the wrong length"
+        );
+        metadata.extend_from_slice(data);
+    }
+
+    anyhow::ensure!(
+        *Hash::new(&metadata) == info_hash,
+        "metadata doesn't match the info hash"
+    );
+    Info::from_bytes(&metadata)
+}
+
+impl FromStr for Magnet {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s.strip_prefix("magnet:?").ok_or(MagnetError::NotMagnet)?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|_| MagnetError::InvalidEncoding)?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    // Other topics, such as v2 `urn:btmh:` hashes, aren't supported.
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value.parse().map_err(|_| MagnetError::InvalidPeer(value))?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// Info hashes come as 40 hex digits or, in older links, as 32 base32 characters.
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let mut info_hash = [0; 20];
+    let valid = match hash.len() {
+        40 => hex::decode_to_slice(hash, &mut info_hash).is_ok(),
+        32 => base32_decode(hash, &mut info_hash),
+        _ => false,
+    };
+
+    if valid {
+        Ok(info_hash)
+    } else {
+        Err(MagnetError::InvalidInfoHash(hash.to_string()))
+    }
+}
+
+/// Decodes unpadded RFC 4648 base32, ignoring case. `encoded` must fill `out` exactly.
+fn base32_decode(encoded: &str, out: &mut [u8]) -> bool {
+    if encoded.len() * 5 != out.len() * 8 {
+        return false;
+    }
+
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    let mut written = 0;
+
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return false,
+        };
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            out[written] = (buffer >> bits) as u8;
+            buffer &= (1 << bits) - 1;
+            written += 1;
+        }
+    }
+
+    true
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MagnetError {
+    #[error("not a magnet link")]
+    NotMagnet,
+    #[error("invalid URL encoding")]
+    InvalidEncoding,
+    #[error("magnet link has no BitTorrent info hash")]
+    MissingInfoHash,
+    #[error("invalid info hash {0:?}")]
+    InvalidInfoHash(String),
+    #[error("invalid peer address {0:?}")]
+    InvalidPeer(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::message::Handshake;
+
+    const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    #[test]
+    fn parses_links() {
+        let magnet: Magnet = format!(
+            "magnet:?xt=urn:btih:{INFO_HASH}&dn=sample.txt\
+             &tr=http%3A%2F%2Ftracker%2Fannounce%3Fkey%3D1&tr=udp://backup:80&x.pe=127.0.0.1:6881\
+             &x.pe=%5B%3A%3A1%5D%3A6881"
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+        assert_eq!(magnet.name.as_deref(), Some("sample.txt"));
+        assert_eq!(
+            magnet.trackers,
+            ["http://tracker/announce?key=1", "udp://backup:80"]
+        );
+        assert_eq!(
+            magnet.peers,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:6881".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_base32_info_hashes() {
+        let magnet: Magnet = "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7"
+            .parse()
+            .unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+
+        let magnet: Magnet = "magnet:?xt=urn:btih:22pzdzvsvzgfijdi2edtu4ou5ijypgt7"
+            .parse()
+            .unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+    }
+
+    #[test]
+    fn rejects_invalid_links() {
+        assert_eq!(
+            "http://example.com".parse::<Magnet>(),
+            Err(MagnetError::NotMagnet)
+        );
+        assert_eq!(
+            "magnet:?dn=x".parse::<Magnet>(),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            "magnet:?xt=urn:btih:1234".parse::<Magnet>(),
+            Err(MagnetError::InvalidInfoHash("1234".to_string()))
+        );
+        assert_eq!(
+            "magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPG01".parse::<Magnet>(),
+            Err(MagnetError::InvalidInfoHash(
+                "22PZDZVSVZGFIJDI2EDTU4OU5IJYPG01".to_string()
+            ))
+        );
+        assert_eq!(
+            format!("magnet:?xt=urn:btih:{INFO_HASH}&x.pe=somewhere").parse::<Magnet>(),
+            Err(MagnetError::InvalidPeer("somewhere".to_string()))
+        );
+    }
+
+    async fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+        let length = stream.read_u32().await.unwrap();
+        let mut message = vec![0; length as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        message
+    }
+
+    async fn write_message(stream: &mut TcpStream, tag: u8, payload: &[u8]) {
+        stream.write_u32(payload.len() as u32 + 1).await.unwrap();
+        stream.write_u8(tag).await.unwrap();
+        stream.write_all(payload).await.unwrap();
+    }
+
+    /// Serves `metadata` the way a seeding peer would.
+    async fn seeder(metadata: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = Handshake::new(*Hash::new(&metadata));
+            let mut theirs = [0; 68];
+            stream.read_exact(&mut theirs).await.unwrap();
+            stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+            write_message(&mut stream, 5, &[0xff]).await;
+
+            let message = read_message(&mut stream).await;
+            assert_eq!(&message[..2], [20, 0]);
+            let mut ours = b"\x00d1:md11:ut_metadatai3ee13:metadata_size".to_vec();
+            ours.extend(format!("i{}ee", metadata.len()).bytes());
+            write_message(&mut stream, 20, &ours).await;
+
+            for chunk in metadata.chunks(METADATA_PIECE) {
+                let message = read_message(&mut stream).await;
+                assert_eq!(&message[..2], [20, 3]);
+                let request = Bencode::new(&message[2..]).unwrap();
+                let mut response = vec![1];
+                [
+                    ("msg_type", Bencode::from(1)),
+                    ("piece", request.query("piece").unwrap()[0].clone()),
+                    ("total_size", Bencode::from(metadata.len() as isize)),
+                ]
+                .into_iter()
+                .collect::<Bencode>()
+                .encode_to(&mut response);
+                response.extend_from_slice(chunk);
+                write_message(&mut stream, 20, &response).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn fetches_metadata() {
+        let mut metadata =
+            b"d6:lengthi40000e4:name4:test12:piece lengthi16384e6:pieces20000:".to_vec();
+        metadata.extend((0..20_000).map(|i| i as u8));
+        metadata.push(b'e');
+
+        let magnet = Magnet {
+            info_hash: *Hash::new(&metadata),
+            name: None,
+            trackers: vec!["http://tracker/announce".to_string()],
+            peers: Vec::new(),
+        };
+        let addr = seeder(metadata).await;
+        let torrent = magnet.torrent_from(addr).await.unwrap();
+
+        assert_eq!(torrent.info.name, "test");
+        assert_eq!(torrent.info.length(), 40_000);
+        assert_eq!(torrent.info.pieces.len(), 1000);
+        assert_eq!(torrent.info_hash().unwrap(), magnet.info_hash);
+        assert_eq!(torrent.announce, "http://tracker/announce");
+    }
+}
@@ -2,9 +2,18 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 use clap::{Parser, Subcommand};
+use tokio::io::AsyncReadExt;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{fmt::layer, prelude::*};
 
-use bittorrent_starter_rust::{bencode::Bencode, peer::*, torrent::*};
+use bittorrent_starter_rust::{
+    bencode::Bencode,
+    magnet::Magnet,
+    peer::*,
+    resume::Resume,
+    storage::{FileStorage, MmapStorage, Storage},
+    torrent::*,
+};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -18,6 +27,20 @@ struct Args {
 enum Commands {
     Decode {
         encoded_value: String,
+        /// Read the value from the file at `encoded_value` instead
+        #[arg(short, long)]
+        file: bool,
+    },
+    Encode {
+        #[arg(short)]
+        output: PathBuf,
+        /// JSON file to encode, or `-` to read from stdin
+        input: PathBuf,
+    },
+    Query {
+        file: PathBuf,
+        /// Path of the values to print, e.g. `info.files[*].path`
+        path: String,
     },
     Info {
         torrent: PathBuf,
@@ -25,6 +48,9 @@ enum Commands {
     Peers {
         torrent: PathBuf,
     },
+    Scrape {
+        torrent: PathBuf,
+    },
     Handshake {
         torrent: PathBuf,
         peer: String,
@@ -38,7 +64,40 @@ enum Commands {
     Download {
         #[arg(short)]
         output: PathBuf,
+        /// Torrent file or magnet link
+        torrent: String,
+        /// Write the content through memory-mapped files
+        #[arg(long)]
+        mmap: bool,
+    },
+    Verify {
         torrent: PathBuf,
+        /// Downloaded file or directory to check
+        path: PathBuf,
+    },
+    MagnetParse {
+        link: String,
+    },
+    MagnetInfo {
+        link: String,
+    },
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        /// File or directory to create the torrent for
+        path: PathBuf,
+        /// Tracker URL, repeat to add backup trackers
+        #[arg(short, long, required = true)]
+        announce: Vec<String>,
+        /// URL of an HTTP server mirroring the content, repeatable
+        #[arg(long)]
+        web_seed: Vec<String>,
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        private: bool,
     },
 }
 
@@ -51,32 +110,71 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Commands::Decode { encoded_value } => {
+        Commands::Decode {
+            encoded_value,
+            file,
+        } => {
+            let encoded_value = if file {
+                tokio::fs::read(&encoded_value)
+                    .await
+                    .context("read encoded file")?
+            } else {
+                encoded_value.into_bytes()
+            };
             let decoded_value = Bencode::new(&encoded_value)?;
             let value: serde_json::Value = (&decoded_value).into();
             println!("{}", value);
         }
+        Commands::Encode { output, input } => {
+            let json = if input.as_os_str() == "-" {
+                let mut json = Vec::new();
+                tokio::io::stdin()
+                    .read_to_end(&mut json)
+                    .await
+                    .context("read JSON from stdin")?;
+                json
+            } else {
+                tokio::fs::read(&input).await.context("read JSON file")?
+            };
+            let value: serde_json::Value = serde_json::from_slice(&json).context("parse JSON")?;
+            let encoded_value = Bencode::try_from(&value)?.encode();
+
+            tokio::fs::write(&output, encoded_value)
+                .await
+                .context("write out encoded value")?;
+        }
+        Commands::Query { file, path } => {
+            let encoded_value = tokio::fs::read(&file).await.context("read encoded file")?;
+            let decoded_value = Bencode::new(&encoded_value)?;
+            let matches = decoded_value.query(&path)?;
+            anyhow::ensure!(!matches.is_empty(), "nothing matches {path}");
+
+            for value in matches {
+                println!("{}", serde_json::Value::from(value));
+            }
+        }
         Commands::Info { torrent } => {
             let torrent = Torrent::new(torrent).await?;
-            let Keys::SingleFile { length } = torrent.info.keys;
-            let info_hash = hex::encode(torrent.info_hash()?);
-
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", length);
-            println!("Info Hash: {}", info_hash);
-            println!("Piece Length: {}", torrent.info.piece_length);
-            println!("Piece Hashes:");
-            for piece_hash in torrent.piece_hashes() {
-                println!("{piece_hash}");
-            }
+            print_info(&torrent)?;
         }
         Commands::Peers { torrent } => {
             let torrent = Torrent::new(torrent).await?;
 
             for peer in torrent.peers().await?.iter() {
-                println!("{}:{}", peer.addr().ip(), peer.addr().port());
+                println!("{}", peer.addr());
             }
         }
+        Commands::Scrape { torrent } => {
+            let torrent = Torrent::new(torrent).await?;
+            let scrape = torrent
+                .scrape()
+                .await?
+                .context("the tracker doesn't track this torrent")?;
+
+            println!("Seeders: {}", scrape.complete);
+            println!("Leechers: {}", scrape.incomplete);
+            println!("Completed: {}", scrape.downloaded);
+        }
         Commands::Handshake { torrent, peer } => {
             let torrent = Torrent::new(torrent).await?;
 
@@ -100,17 +198,173 @@ async fn main() -> anyhow::Result<()> {
 
             println!("Piece {piece} downloaded to {}", output.display());
         }
-        Commands::Download { output, torrent } => {
+        Commands::Download {
+            output,
+            torrent,
+            mmap,
+        } => {
+            let torrent = load_torrent(&torrent).await?;
+            let resume_path = Resume::path_for(&output);
+            let existed = output.exists();
+            let resumed = if existed {
+                Resume::load(&torrent, &output, &resume_path)?
+            } else {
+                None
+            };
+
+            let mut storage: Box<dyn Storage> = if mmap {
+                Box::new(MmapStorage::create(&torrent.info, &output)?)
+            } else {
+                Box::new(FileStorage::create(&torrent.info, &output)?)
+            };
+            let mut resume = match resumed {
+                Some(resume) => resume,
+                None if existed => Resume::check(&torrent, storage.as_mut(), &output, resume_path)?,
+                None => Resume::new(&torrent, &output, resume_path)?,
+            };
+            // Ctrl-C stops the download cleanly, so it can be resumed later.
+            let cancel = CancellationToken::new();
+            tokio::spawn({
+                let cancel = cancel.clone();
+                async move {
+                    if tokio::signal::ctrl_c().await.is_ok() {
+                        cancel.cancel();
+                    }
+                }
+            });
+            torrent
+                .download_missing(storage.as_mut(), &mut resume, &cancel)
+                .await?;
+
+            println!("File downloaded to {}", output.display());
+        }
+        Commands::Verify { torrent, path } => {
             let torrent = Torrent::new(torrent).await?;
-            let data = torrent.download().await?;
+            let verification = tokio::task::spawn_blocking(move || torrent.verify(&path)).await??;
 
-            tokio::fs::write(&output, data)
+            println!("Good: {}", verification.count(PieceStatus::Good));
+            println!("Bad: {}", verification.count(PieceStatus::Bad));
+            println!("Missing: {}", verification.count(PieceStatus::Missing));
+            for (file, good) in &verification.files {
+                let percent = if file.length == 0 {
+                    100.0
+                } else {
+                    *good as f64 * 100.0 / file.length as f64
+                };
+                println!("{}: {percent:.1}%", file.path.join("/"));
+            }
+
+            anyhow::ensure!(verification.is_complete(), "data doesn't match the torrent");
+        }
+        Commands::MagnetParse { link } => {
+            let magnet: Magnet = link.parse()?;
+
+            for tracker in &magnet.trackers {
+                println!("Tracker URL: {tracker}");
+            }
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+            if let Some(name) = &magnet.name {
+                println!("Name: {name}");
+            }
+            for peer in &magnet.peers {
+                println!("Peer: {peer}");
+            }
+        }
+        Commands::MagnetInfo { link } => {
+            let magnet: Magnet = link.parse()?;
+            let torrent = magnet.torrent().await?;
+            print_info(&torrent)?;
+        }
+        Commands::Create {
+            output,
+            path,
+            announce,
+            web_seed,
+            piece_length,
+            comment,
+            private,
+        } => {
+            let mut builder = TorrentBuilder::new(path)
+                .created_by(concat!(
+                    env!("CARGO_PKG_NAME"),
+                    " ",
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .private(private);
+            if announce.len() > 1 {
+                for url in announce {
+                    builder = builder.announce_tier(vec![url]);
+                }
+            } else {
+                builder = builder.announce(announce[0].clone());
+            }
+            for url in web_seed {
+                builder = builder.web_seed(url);
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+
+            let torrent = tokio::task::spawn_blocking(move || builder.build()).await??;
+
+            tokio::fs::write(&output, torrent.to_bytes()?)
                 .await
-                .context("write out downloaded file")?;
+                .context("write out torrent file")?;
 
-            println!("File downloaded to {}", output.display());
+            println!("Torrent created at {}", output.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn print_info(torrent: &Torrent) -> anyhow::Result<()> {
+    let info_hash = hex::encode(torrent.info_hash()?);
+
+    println!("Tracker URL: {}", torrent.announce);
+    println!("Length: {}", torrent.info.length());
+    println!("Info Hash: {}", info_hash);
+    println!("Piece Length: {}", torrent.info.piece_length);
+    println!("Piece Hashes:");
+    for piece_hash in torrent.piece_hashes() {
+        println!("{piece_hash}");
+    }
+    if let Some(announce_list) = &torrent.announce_list {
+        println!("Tracker Tiers:");
+        for (tier, trackers) in announce_list.iter().enumerate() {
+            println!("{tier}: {}", trackers.join(" "));
+        }
+    }
+    for seed in torrent.web_seeds() {
+        println!("Web Seed: {}", seed.url());
+    }
+    if torrent.info.is_v2() {
+        println!("Info Hash v2: {}", hex::encode(torrent.info.hash_v2()?));
+    }
+    if !matches!(torrent.info.keys, Keys::SingleFile { .. }) {
+        println!("Files:");
+        for file in torrent
+            .info
+            .files()
+            .iter()
+            .filter(|file| !file.is_padding())
+        {
+            println!("{} ({})", file.path.join("/"), file.length);
         }
     }
 
     Ok(())
 }
+
+/// Reads a torrent file, or fetches the info dictionary of a magnet link from its peers.
+async fn load_torrent(torrent: &str) -> anyhow::Result<Torrent> {
+    if torrent.starts_with("magnet:") {
+        let magnet: Magnet = torrent.parse()?;
+        magnet.torrent().await
+    } else {
+        Torrent::new(torrent.into()).await
+    }
+}
@@ -3,6 +3,9 @@ use tokio_util::codec::{Decoder, Encoder};
 
 const MAX: usize = 1 << 16;
 
+/// Bit of the handshake's `reserved` bytes that advertises the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct Handshake {
@@ -15,15 +18,22 @@ pub struct Handshake {
 
 impl Handshake {
     pub fn new(info_hash: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id: *b"17273747576777879707",
         }
     }
 
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let ptr = self as *mut Self as *mut u8;
         // Safety: Handshake is a POD with repr(C)
@@ -44,6 +54,7 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Extended = 20,
 }
 
 impl TryFrom<u8> for MessageTag {
@@ -60,6 +71,7 @@ impl TryFrom<u8> for MessageTag {
             6 => Self::Request,
             7 => Self::Piece,
             8 => Self::Cancel,
+            20 => Self::Extended,
             n => return Err(n),
         };
 
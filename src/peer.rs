@@ -1,4 +1,4 @@
-use std::{marker::PhantomData, net::SocketAddrV4};
+use std::{collections::VecDeque, marker::PhantomData, net::SocketAddr};
 
 use anyhow::Context;
 use futures_util::{SinkExt, StreamExt};
@@ -8,13 +8,28 @@ use tokio::{
 };
 use tokio_util::codec::Framed;
 
-use crate::{message::*, torrent::BLOCK_MAX};
+use crate::{
+    extension::{ExtensionHandshake, Extensions, HANDSHAKE_ID},
+    message::*,
+    torrent::BLOCK_MAX,
+};
+
+/// Most messages kept for later while waiting for a specific one. A peer that sends more than
+/// this meanwhile is flooding the connection.
+const MAX_BACKLOG: usize = 1024;
 
 pub struct NoId;
-pub struct Id([u8; 20]);
+pub struct Id {
+    peer_id: [u8; 20],
+    extensions: bool,
+}
 
 pub struct NoSession;
-pub struct Session(Framed<TcpStream, MessageFramer>);
+pub struct Session {
+    framed: Framed<TcpStream, MessageFramer>,
+    /// Messages read while waiting for a specific one, to be handed out first.
+    backlog: VecDeque<Message>,
+}
 
 pub struct NoPieces;
 pub struct Pieces(Vec<usize>);
@@ -23,24 +38,33 @@ pub struct NotReady;
 pub struct Ready;
 
 pub struct Peer<I, S, P, T> {
-    addr: SocketAddrV4,
+    addr: SocketAddr,
     id: I,
     session: S,
     pieces: P,
+    extensions: Extensions,
     state: PhantomData<T>,
 }
 
 impl Peer<NoId, NoSession, NoPieces, NotReady> {
-    pub fn new(addr: SocketAddrV4) -> Self {
+    pub fn new(addr: SocketAddr) -> Self {
         Self {
             addr,
             id: NoId,
             session: NoSession,
             pieces: NoPieces,
+            extensions: Extensions::new(),
             state: PhantomData,
         }
     }
 
+    /// Extensions to offer the peer. The extension handshake is sent right after the bittorrent
+    /// handshake if both ends support the extension protocol.
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
     pub async fn handshake(
         self,
         info_hash: [u8; 20],
@@ -52,24 +76,33 @@ impl Peer<NoId, NoSession, NoPieces, NotReady> {
         stream.read_exact(bytes).await?;
         anyhow::ensure!(handshake.length == 19);
         anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
-        Ok(Peer {
+        let mut peer = Peer {
             addr: self.addr,
-            id: Id(handshake.peer_id),
-            session: Session(Framed::new(stream, MessageFramer)),
+            id: Id {
+                peer_id: handshake.peer_id,
+                extensions: handshake.supports_extensions(),
+            },
+            session: Session {
+                framed: Framed::new(stream, MessageFramer),
+                backlog: VecDeque::new(),
+            },
             pieces: self.pieces,
+            extensions: self.extensions,
             state: PhantomData,
-        })
+        };
+
+        if peer.supports_extensions() && !peer.extensions.is_empty() {
+            let handshake = peer.extensions.handshake().to_bytes()?;
+            peer.send_extended_raw(HANDSHAKE_ID, &handshake).await?;
+        }
+
+        Ok(peer)
     }
 }
 
 impl Peer<Id, Session, NoPieces, NotReady> {
     pub async fn bitfield(mut self) -> anyhow::Result<Peer<Id, Session, Pieces, NotReady>> {
-        let bitfield = self
-            .session_mut()
-            .next()
-            .await
-            .expect("peer always sends a bitfields")
-            .context("peer message was invalid")?;
+        let bitfield = self.next_message().await.context("receive bitfield")?;
         anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
 
         let pieces = bitfield
@@ -85,6 +118,7 @@ impl Peer<Id, Session, NoPieces, NotReady> {
             id: self.id,
             session: self.session,
             pieces: Pieces(pieces),
+            extensions: self.extensions,
             state: PhantomData,
         })
     }
@@ -100,12 +134,7 @@ impl Peer<Id, Session, Pieces, NotReady> {
             .await
             .context("send interested message")?;
 
-        let unchoke = self
-            .session_mut()
-            .next()
-            .await
-            .expect("peer always sends an unchoke")
-            .context("peer message was invalid")?;
+        let unchoke = self.next_message().await.context("receive unchoke")?;
         anyhow::ensure!(unchoke.tag == MessageTag::Unchoke);
         anyhow::ensure!(unchoke.payload.is_empty());
 
@@ -114,6 +143,7 @@ impl Peer<Id, Session, Pieces, NotReady> {
             id: self.id,
             session: self.session,
             pieces: self.pieces,
+            extensions: self.extensions,
             state: PhantomData,
         })
     }
@@ -134,12 +164,7 @@ impl Peer<Id, Session, Pieces, Ready> {
                 )
             })?;
 
-        let piece = self
-            .session_mut()
-            .next()
-            .await
-            .expect("peer always sends a piece")
-            .context("peer message was invalid")?;
+        let piece = self.next_message().await.context("receive piece")?;
         anyhow::ensure!(piece.tag == MessageTag::Piece);
         anyhow::ensure!(!piece.payload.is_empty());
 
@@ -155,20 +180,144 @@ impl Peer<Id, Session, Pieces, Ready> {
 }
 
 impl<I, S, P, T> Peer<I, S, P, T> {
-    pub fn addr(&self) -> &SocketAddrV4 {
+    pub fn addr(&self) -> &SocketAddr {
         &self.addr
     }
 }
 
 impl<S, P, T> Peer<Id, S, P, T> {
     pub fn id(&self) -> &[u8; 20] {
-        &self.id.0
+        &self.id.peer_id
+    }
+
+    /// Whether the peer advertised the extension protocol (BEP 10) in its handshake.
+    pub fn supports_extensions(&self) -> bool {
+        self.id.extensions
     }
 }
 
 impl<I, P, T> Peer<I, Session, P, T> {
     pub fn session_mut(&mut self) -> &mut Framed<TcpStream, MessageFramer> {
-        &mut self.session.0
+        &mut self.session.framed
+    }
+
+    /// The next message from the peer. Extension handshakes are recorded and not returned.
+    async fn next_message(&mut self) -> anyhow::Result<Message> {
+        match self.session.backlog.pop_front() {
+            Some(message) => Ok(message),
+            None => self.read_message().await,
+        }
+    }
+
+    /// Reads a message off the connection, skipping the backlog.
+    async fn read_message(&mut self) -> anyhow::Result<Message> {
+        loop {
+            if let Some(message) = self.read_frame().await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Reads a single frame. Extension handshakes are recorded and yield `None`.
+    async fn read_frame(&mut self) -> anyhow::Result<Option<Message>> {
+        let message = self
+            .session
+            .framed
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer message was invalid")?;
+
+        if message.tag == MessageTag::Extended && message.payload.first() == Some(&HANDSHAKE_ID) {
+            let handshake = ExtensionHandshake::from_bytes(&message.payload[1..])?;
+            self.extensions.set_remote(handshake);
+            return Ok(None);
+        }
+
+        Ok(Some(message))
+    }
+
+    async fn send_extended_raw(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<()> {
+        let mut message = Vec::with_capacity(1 + payload.len());
+        message.push(id);
+        message.extend_from_slice(payload);
+        self.session_mut()
+            .send(Message {
+                tag: MessageTag::Extended,
+                payload: message,
+            })
+            .await
+            .context("send extended message")
+    }
+}
+
+impl<P, T> Peer<Id, Session, P, T> {
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Waits for the peer's extension handshake. Other messages that arrive meanwhile are kept
+    /// for later.
+    pub async fn extension_handshake(&mut self) -> anyhow::Result<&ExtensionHandshake> {
+        anyhow::ensure!(
+            self.supports_extensions(),
+            "peer doesn't support extensions"
+        );
+
+        while self.extensions.remote().is_none() {
+            if let Some(message) = self.read_frame().await? {
+                self.keep_for_later(message)?;
+            }
+        }
+
+        Ok(self.extensions.remote().expect("handshake has arrived"))
+    }
+
+    /// Sends a message of a registered extension the peer speaks.
+    pub async fn send_extended(&mut self, name: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let id = self
+            .extensions
+            .remote_id(name)
+            .with_context(|| format!("peer doesn't speak {name}"))?;
+        self.send_extended_raw(id, payload).await
+    }
+
+    /// Waits for a message of a registered extension and returns its payload. Other messages that
+    /// arrive meanwhile are kept for later.
+    pub async fn receive_extended(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let id = self
+            .extensions
+            .local_id(name)
+            .with_context(|| format!("{name} isn't registered"))?;
+        let is_wanted = |message: &Message| {
+            message.tag == MessageTag::Extended && message.payload.first() == Some(&id)
+        };
+
+        if let Some(position) = self.session.backlog.iter().position(is_wanted) {
+            let message = self
+                .session
+                .backlog
+                .remove(position)
+                .expect("position is in range");
+            return Ok(message.payload[1..].to_vec());
+        }
+
+        loop {
+            let message = self.read_message().await?;
+            if is_wanted(&message) {
+                return Ok(message.payload[1..].to_vec());
+            }
+            self.keep_for_later(message)?;
+        }
+    }
+
+    fn keep_for_later(&mut self, message: Message) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.session.backlog.len() < MAX_BACKLOG,
+            "peer sent over {MAX_BACKLOG} messages while we waited for one"
+        );
+        self.session.backlog.push_back(message);
+        Ok(())
     }
 }
 
@@ -179,9 +328,47 @@ impl<I, S, T> Peer<I, S, Pieces, T> {
 }
 
 impl TryFrom<String> for Peer<NoId, NoSession, NoPieces, NotReady> {
-    type Error = <std::net::SocketAddrV4 as std::str::FromStr>::Err;
+    type Error = std::net::AddrParseError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         Ok(Self::new(value.parse()?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn parses_peer_addresses() {
+        let peer = Peer::try_from("127.0.0.1:6881".to_string()).unwrap();
+        assert_eq!(*peer.addr(), SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)));
+        let peer = Peer::try_from("[::1]:6881".to_string()).unwrap();
+        assert_eq!(*peer.addr(), SocketAddr::from((Ipv6Addr::LOCALHOST, 6881)));
+        assert!(Peer::try_from("::1:6881".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn drops_flooding_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = Handshake::new([0; 20]);
+            stream.read_exact(handshake.as_bytes_mut()).await.unwrap();
+            stream.write_all(handshake.as_bytes_mut()).await.unwrap();
+            // Keep-alives are skipped, so send have messages instead of an extension handshake.
+            for _ in 0..=MAX_BACKLOG {
+                let _ = stream.write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 0]).await;
+            }
+        });
+
+        let mut peer = Peer::new(addr).handshake([0; 20]).await.unwrap();
+        assert!(peer.extension_handshake().await.is_err());
+        assert_eq!(peer.session.backlog.len(), MAX_BACKLOG);
+    }
+}
@@ -0,0 +1,235 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tracing::{info, warn};
+
+use crate::{
+    bencode,
+    storage::{self, Storage},
+    torrent::Torrent,
+};
+
+/// The fast-resume file of a download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FastResume {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+
+    /// The pieces on disk, as a bitfield with the first piece in the high bit.
+    pieces: ByteBuf,
+
+    /// When each file was last modified as of saving, in nanoseconds since the epoch. Files that
+    /// changed since can't be trusted to still hold their pieces.
+    mtimes: Vec<u64>,
+}
+
+/// Which pieces of a download are on disk, kept in a fast-resume file so an interrupted download
+/// picks up where it left off.
+#[derive(Debug, Clone)]
+pub struct Resume {
+    path: PathBuf,
+    files: Vec<Option<PathBuf>>,
+    info_hash: [u8; 20],
+    have: Vec<bool>,
+}
+
+impl Resume {
+    /// Where the fast-resume file of a download to `output` goes, next to it.
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut path = OsString::from(output);
+        path.push(".resume");
+        path.into()
+    }
+
+    /// Progress of a download that hasn't started, with no pieces on disk.
+    pub fn new(torrent: &Torrent, output: &Path, path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: path.into(),
+            files: storage::file_paths(&torrent.info, output)?,
+            info_hash: torrent.info_hash()?,
+            have: vec![false; torrent.info.pieces.len()],
+        })
+    }
+
+    /// Progress of an interrupted download to `output`, read from the fast-resume file at `path`.
+    /// `None` when there's no such file, or it doesn't match the files on disk. Load it before
+    /// opening storage on the files, so the state they were left in is what gets compared.
+    pub fn load(
+        torrent: &Torrent,
+        output: &Path,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Option<Self>> {
+        let mut resume = Self::new(torrent, output, path)?;
+
+        match resume.read() {
+            Ok(Some(have)) => {
+                info!("resuming from {}", resume.path.display());
+                resume.have = have;
+                Ok(Some(resume))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                warn!("checking existing data instead of resuming: {e:#}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Progress of a download to `output` found by checking every piece in `storage` against its
+    /// hash, for when there's no fast-resume file to trust.
+    pub fn check(
+        torrent: &Torrent,
+        storage: &mut dyn Storage,
+        output: &Path,
+        path: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let mut resume = Self::new(torrent, output, path)?;
+
+        info!("checking existing data in {}", output.display());
+        for piece in 0..resume.have.len() {
+            resume.have[piece] = storage.verify_piece(&torrent.info, piece)?;
+        }
+
+        Ok(resume)
+    }
+
+    pub fn have(&self, piece: usize) -> bool {
+        self.have.get(piece).copied().unwrap_or(false)
+    }
+
+    /// The pieces still to download, in order.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.have.len())
+            .filter(|&piece| !self.have[piece])
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.iter().all(|&have| have)
+    }
+
+    pub fn mark(&mut self, piece: usize) {
+        self.have[piece] = true;
+    }
+
+    /// Writes the fast-resume file. The pieces it lists must have been flushed to storage first.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut pieces = vec![0; self.have.len().div_ceil(8)];
+        for (piece, _) in self.have.iter().enumerate().filter(|(_, &have)| have) {
+            pieces[piece / 8] |= 0x80 >> (piece % 8);
+        }
+
+        let fast_resume = FastResume {
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            pieces: ByteBuf::from(pieces),
+            mtimes: self.mtimes(),
+        };
+
+        // Written aside and renamed over, so a crash never leaves half a file behind.
+        let mut tmp = OsString::from(&self.path);
+        tmp.push(".tmp");
+        std::fs::write(&tmp, bencode::to_bytes(&fast_resume)?).context("write resume file")?;
+        std::fs::rename(&tmp, &self.path).context("replace resume file")
+    }
+
+    /// The pieces listed in the fast-resume file, unless there is none. Fails when it's for
+    /// another torrent or the files changed since it was saved.
+    fn read(&self) -> anyhow::Result<Option<Vec<bool>>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read resume file"),
+        };
+        let fast_resume: FastResume = bencode::from_bytes(&data).context("parse resume file")?;
+
+        anyhow::ensure!(
+            *fast_resume.info_hash == self.info_hash,
+            "resume file is for another torrent"
+        );
+        anyhow::ensure!(
+            fast_resume.pieces.len() == self.have.len().div_ceil(8),
+            "resume file has the wrong number of pieces"
+        );
+        anyhow::ensure!(
+            fast_resume.mtimes == self.mtimes(),
+            "files changed since the resume file was saved"
+        );
+
+        Ok(Some(
+            (0..self.have.len())
+                .map(|piece| fast_resume.pieces[piece / 8] & (0x80 >> (piece % 8)) != 0)
+                .collect(),
+        ))
+    }
+
+    /// Modification times of the files, with 0 for padding and files that don't exist.
+    fn mtimes(&self) -> Vec<u64> {
+        self.files
+            .iter()
+            .map(|path| {
+                path.as_ref()
+                    .and_then(|path| std::fs::metadata(path).ok())
+                    .and_then(|metadata| metadata.modified().ok())
+                    .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |mtime| mtime.as_nanos() as u64)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::storage::{FileStorage, MmapStorage};
+    use crate::test_support::sample_torrent;
+
+    #[test]
+    fn resumes_downloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, a, b) = sample_torrent(&dir.path().join("content"));
+        let content = [a, b].concat();
+
+        let output = dir.path().join("output");
+        let path = Resume::path_for(&output);
+        assert_eq!(path, dir.path().join("output.resume"));
+
+        // Without a resume file the data on disk is checked.
+        let mut storage = FileStorage::create(&torrent.info, &output).unwrap();
+        storage.write_block(0, 0, &content[..1 << 14]).unwrap();
+        storage
+            .write_block(2, 0, &content[2 << 14..3 << 14])
+            .unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        assert!(Resume::load(&torrent, &output, &path).unwrap().is_none());
+        let mut storage = FileStorage::create(&torrent.info, &output).unwrap();
+        let mut resume = Resume::check(&torrent, &mut storage, &output, &path).unwrap();
+        assert_eq!(resume.missing(), [1, 3]);
+
+        // With one, the pieces it lists are trusted as long as the files are untouched, which
+        // opening storage on them again doesn't count as.
+        resume.mark(1);
+        resume.save().unwrap();
+        drop(storage);
+        let resume = Resume::load(&torrent, &output, &path).unwrap().unwrap();
+        assert_eq!(resume.missing(), [3]);
+        let storage = MmapStorage::create(&torrent.info, &output).unwrap();
+        drop(storage);
+        let resume = Resume::load(&torrent, &output, &path).unwrap().unwrap();
+        assert_eq!(resume.missing(), [3]);
+
+        let file = std::fs::File::options()
+            .write(true)
+            .open(output.join("a"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(Resume::load(&torrent, &output, &path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::{
+    torrent::{piece_size, Info, Keys},
+    Hash,
+};
+
+pub use file::FileStorage;
+pub use memory::MemoryStorage;
+pub use mmap::MmapStorage;
+
+mod file;
+mod memory;
+mod mmap;
+
+/// Where the pieces of a torrent are kept while it downloads.
+///
+/// Blocks are addressed the way peers request them: by piece, and offset in the piece.
+/// Implementations are free to lay the content out however they like.
+pub trait Storage: Send {
+    /// Reads `length` bytes at `begin` in `piece`.
+    fn read_block(&mut self, piece: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Writes `data` at `begin` in `piece`.
+    fn write_block(&mut self, piece: usize, begin: usize, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Makes every write so far durable.
+    fn flush(&mut self) -> anyhow::Result<()>;
+
+    /// Checks the stored data of `piece` against its hash.
+    fn verify_piece(&mut self, info: &Info, piece: usize) -> anyhow::Result<bool> {
+        let hash = info
+            .pieces
+            .get(piece)
+            .with_context(|| format!("torrent has no piece {piece}"))?;
+        let data = self.read_block(
+            piece,
+            0,
+            piece_size(piece, info.length(), info.piece_length),
+        )?;
+
+        Ok(*Hash::new(data) == *hash)
+    }
+}
+
+/// Where each file of the torrent is stored under `output`, in content order. Padding files are
+/// never stored and have no path.
+pub(crate) fn file_paths(info: &Info, output: &Path) -> anyhow::Result<Vec<Option<PathBuf>>> {
+    Ok(Layout::new(info, output)?
+        .files
+        .into_iter()
+        .map(|file| file.path)
+        .collect())
+}
+
+/// Where each file of a torrent sits in its content.
+#[derive(Debug, Clone)]
+struct Layout {
+    files: Vec<LayoutFile>,
+    piece_length: usize,
+    length: usize,
+}
+
+#[derive(Debug, Clone)]
+struct LayoutFile {
+    offset: usize,
+    length: usize,
+    /// Padding files have no path, as they're never written to disk.
+    path: Option<PathBuf>,
+}
+
+/// The part of a read or write that falls in one file.
+#[derive(Debug)]
+struct Span {
+    /// Index of the file in the layout.
+    file: usize,
+    /// Range of the data that falls in the file.
+    range: Range<usize>,
+    /// Offset of that range in the file.
+    at: usize,
+}
+
+impl Layout {
+    /// Lays out the files of the torrent under `output`, or at `output` itself for single-file
+    /// torrents.
+    fn new(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        let mut offset = 0;
+
+        for entry in info.files() {
+            let path = if entry.is_padding() {
+                None
+            } else {
+                Some(match &info.keys {
+                    Keys::SingleFile { .. } => output.to_path_buf(),
+                    Keys::MultiFile { .. } | Keys::FileTree {} => {
+                        output.join(entry.relative_path()?)
+                    }
+                })
+            };
+
+            files.push(LayoutFile {
+                offset,
+                length: entry.length,
+                path,
+            });
+            offset += entry.length;
+        }
+
+        Ok(Self {
+            files,
+            piece_length: info.piece_length,
+            length: offset,
+        })
+    }
+
+    fn offset(&self, piece: usize, begin: usize) -> usize {
+        piece * self.piece_length + begin
+    }
+
+    /// The files that `offset..offset + length` of the content overlaps, leaving out padding and
+    /// empty files.
+    fn spans(&self, offset: usize, length: usize) -> anyhow::Result<Vec<Span>> {
+        let end = offset + length;
+        anyhow::ensure!(
+            end <= self.length,
+            "{offset}..{end} is past the end of the content"
+        );
+
+        Ok(self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.path.is_some() && file.length > 0)
+            .filter(|(_, file)| file.offset < end && file.offset + file.length > offset)
+            .map(|(index, file)| {
+                let from = offset.max(file.offset);
+                let to = end.min(file.offset + file.length);
+                Span {
+                    file: index,
+                    range: from - offset..to - offset,
+                    at: from - file.offset,
+                }
+            })
+            .collect())
+    }
+
+    /// Creates every file at its full length, so pieces can be written at their offsets in
+    /// whatever order they arrive. Files that already exist keep their data. Padding files get
+    /// `None`.
+    fn create_files(&self) -> anyhow::Result<Vec<Option<File>>> {
+        self.files
+            .iter()
+            .map(|file| {
+                let Some(path) = &file.path else {
+                    return Ok(None);
+                };
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).context("create directory")?;
+                }
+
+                let handle = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)
+                    .with_context(|| format!("open {}", path.display()))?;
+                // Resizing bumps the modification time even to the same length, which would
+                // make fast-resume files look stale.
+                let length = handle
+                    .metadata()
+                    .with_context(|| format!("stat {}", path.display()))?
+                    .len();
+                if length != file.length as u64 {
+                    handle
+                        .set_len(file.length as u64)
+                        .with_context(|| format!("allocate {}", path.display()))?;
+                }
+
+                Ok(Some(handle))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{sample_torrent, test_torrent};
+
+    /// Writes the pieces back to front and reads them back across the file boundary.
+    fn round_trip(storage: &mut dyn Storage, info: &Info, content: &[u8]) {
+        for piece in (0..info.pieces.len()).rev() {
+            assert!(!storage.verify_piece(info, piece).unwrap());
+            let start = piece * info.piece_length;
+            let end = content.len().min(start + info.piece_length);
+            storage.write_block(piece, 0, &content[start..end]).unwrap();
+            assert!(storage.verify_piece(info, piece).unwrap());
+        }
+        storage.flush().unwrap();
+
+        assert_eq!(
+            storage.read_block(1, 19_000 - (1 << 14), 2_000).unwrap(),
+            content[19_000..21_000]
+        );
+        assert!(storage.write_block(3, 1_000, &[0; 1_000]).is_err());
+    }
+
+    #[test]
+    fn stores_pieces_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, a, b) = sample_torrent(&dir.path().join("content"));
+        let content = [a.clone(), b.clone()].concat();
+
+        let output = dir.path().join("file");
+        let mut storage = FileStorage::create(&torrent.info, &output).unwrap();
+        assert_eq!(
+            std::fs::metadata(output.join("sub/b")).unwrap().len(),
+            30_000
+        );
+        round_trip(&mut storage, &torrent.info, &content);
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), a);
+        assert_eq!(std::fs::read(output.join("sub/b")).unwrap(), b);
+
+        let output = dir.path().join("mmap");
+        let mut storage = MmapStorage::create(&torrent.info, &output).unwrap();
+        round_trip(&mut storage, &torrent.info, &content);
+        drop(storage);
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), a);
+        assert_eq!(std::fs::read(output.join("sub/b")).unwrap(), b);
+
+        let mut storage = MemoryStorage::new(&torrent.info);
+        round_trip(&mut storage, &torrent.info, &content);
+        assert_eq!(storage.into_inner(), content);
+    }
+
+    #[test]
+    fn skips_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = vec![1; 10_000];
+        let c = vec![3; 10_000];
+        let torrent = test_torrent(
+            &dir.path().join("content"),
+            &[("a", &a), ("b", &[]), ("c", &c)],
+        );
+        let content = [a, c].concat();
+
+        // The empty file sits in the middle of the first piece.
+        let output = dir.path().join("mmap");
+        let mut storage = MmapStorage::create(&torrent.info, &output).unwrap();
+        storage.write_block(0, 0, &content[..1 << 14]).unwrap();
+        assert!(storage.verify_piece(&torrent.info, 0).unwrap());
+        drop(storage);
+
+        std::fs::remove_file(output.join("b")).unwrap();
+        let mut storage = FileStorage::open(&torrent.info, &output).unwrap();
+        assert!(storage.is_stored(0, 0, 1 << 14));
+        assert!(storage.verify_piece(&torrent.info, 0).unwrap());
+    }
+}
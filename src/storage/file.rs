@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use super::{Layout, Storage};
+use crate::torrent::Info;
+
+/// Keeps the content in its files on disk, with plain reads and writes.
+#[derive(Debug)]
+pub struct FileStorage {
+    layout: Layout,
+    files: Vec<Option<File>>,
+}
+
+impl FileStorage {
+    /// Creates the files of the torrent under `output`, or at `output` itself for single-file
+    /// torrents.
+    pub fn create(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let layout = Layout::new(info, output)?;
+        let files = layout.create_files()?;
+
+        Ok(Self { layout, files })
+    }
+
+    /// Opens the files of the torrent under `output` as they are, for reading only. Files that
+    /// are missing or shorter than their length are left out.
+    pub fn open(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let layout = Layout::new(info, output)?;
+        let files = layout
+            .files
+            .iter()
+            .map(|file| {
+                let Some(path) = &file.path else {
+                    return Ok(None);
+                };
+                match File::open(path) {
+                    Ok(handle) => {
+                        let length = handle
+                            .metadata()
+                            .with_context(|| format!("stat {}", path.display()))?
+                            .len();
+                        Ok((length >= file.length as u64).then_some(handle))
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e).with_context(|| format!("open {}", path.display())),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { layout, files })
+    }
+
+    /// Whether every file that `length` bytes at `begin` in `piece` span is on disk.
+    pub fn is_stored(&self, piece: usize, begin: usize, length: usize) -> bool {
+        let offset = self.layout.offset(piece, begin);
+        self.layout
+            .spans(offset, length)
+            .is_ok_and(|spans| spans.iter().all(|span| self.files[span.file].is_some()))
+    }
+
+    fn file(&mut self, index: usize) -> anyhow::Result<&mut File> {
+        self.files[index]
+            .as_mut()
+            .context("file is missing from disk")
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(&mut self, piece: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let offset = self.layout.offset(piece, begin);
+        let mut data = vec![0; length];
+
+        for span in self.layout.spans(offset, length)? {
+            let file = self.file(span.file)?;
+            file.seek(SeekFrom::Start(span.at as u64))
+                .context("seek file")?;
+            file.read_exact(&mut data[span.range])
+                .context("read file")?;
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&mut self, piece: usize, begin: usize, data: &[u8]) -> anyhow::Result<()> {
+        let offset = self.layout.offset(piece, begin);
+
+        for span in self.layout.spans(offset, data.len())? {
+            let file = self.file(span.file)?;
+            file.seek(SeekFrom::Start(span.at as u64))
+                .context("seek file")?;
+            file.write_all(&data[span.range]).context("write file")?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for file in self.files.iter_mut().flatten() {
+            file.sync_data().context("sync file")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use super::Storage;
+use crate::torrent::Info;
+
+/// Keeps the whole content in memory. Meant for tests and small torrents.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+    piece_length: usize,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> Self {
+        Self {
+            data: vec![0; info.length()],
+            piece_length: info.piece_length,
+        }
+    }
+
+    /// The content, padding files included.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, piece: usize, begin: usize, length: usize) -> anyhow::Result<(usize, usize)> {
+        let start = piece * self.piece_length + begin;
+        let end = start + length;
+        anyhow::ensure!(
+            end <= self.data.len(),
+            "{start}..{end} is past the end of the content"
+        );
+
+        Ok((start, end))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&mut self, piece: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let (start, end) = self.range(piece, begin, length)?;
+        Ok(self.data[start..end].to_vec())
+    }
+
+    fn write_block(&mut self, piece: usize, begin: usize, data: &[u8]) -> anyhow::Result<()> {
+        let (start, end) = self.range(piece, begin, data.len())?;
+        self.data[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
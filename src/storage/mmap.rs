@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use anyhow::Context;
+use memmap2::MmapMut;
+
+use super::{Layout, Storage};
+use crate::torrent::Info;
+
+/// Keeps the content in its files on disk, mapped into memory so blocks are copied in and out
+/// without a system call each.
+#[derive(Debug)]
+pub struct MmapStorage {
+    layout: Layout,
+    /// Padding and empty files, which can't be mapped, have no map.
+    maps: Vec<Option<MmapMut>>,
+}
+
+impl MmapStorage {
+    /// Creates the files of the torrent under `output`, or at `output` itself for single-file
+    /// torrents, and maps them.
+    pub fn create(info: &Info, output: &Path) -> anyhow::Result<Self> {
+        let layout = Layout::new(info, output)?;
+        let maps = layout
+            .create_files()?
+            .into_iter()
+            .zip(&layout.files)
+            .map(|(file, entry)| match file {
+                Some(file) if entry.length > 0 => {
+                    // SAFETY: the files are ours for the length of the download. Another
+                    // process changing them under us only corrupts data that hash checks catch.
+                    let map = unsafe { MmapMut::map_mut(&file) }.context("map file")?;
+                    Ok(Some(map))
+                }
+                _ => Ok(None),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { layout, maps })
+    }
+
+    fn map(&mut self, index: usize) -> &mut MmapMut {
+        self.maps[index]
+            .as_mut()
+            .expect("spans leave out padding and empty files")
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_block(&mut self, piece: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let offset = self.layout.offset(piece, begin);
+        let mut data = vec![0; length];
+
+        for span in self.layout.spans(offset, length)? {
+            let at = span.at;
+            let len = span.range.len();
+            data[span.range].copy_from_slice(&self.map(span.file)[at..at + len]);
+        }
+
+        Ok(data)
+    }
+
+    fn write_block(&mut self, piece: usize, begin: usize, data: &[u8]) -> anyhow::Result<()> {
+        let offset = self.layout.offset(piece, begin);
+
+        for span in self.layout.spans(offset, data.len())? {
+            let at = span.at;
+            let len = span.range.len();
+            self.map(span.file)[at..at + len].copy_from_slice(&data[span.range]);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for map in self.maps.iter().flatten() {
+            map.flush().context("flush map")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::path::Path;
+
+use crate::torrent::{Torrent, TorrentBuilder};
+
+/// Writes `files`, given by their paths under `root`, and makes a torrent of them with 16 KiB
+/// pieces.
+pub fn test_torrent(root: &Path, files: &[(&str, &[u8])]) -> Torrent {
+    for (path, data) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    TorrentBuilder::new(root)
+        .announce("http://tracker/announce")
+        .piece_length(1 << 14)
+        .build()
+        .unwrap()
+}
+
+/// A torrent of `a` and `sub/b` under `root`, three pieces that cross the file boundary, along
+/// with the contents of both files.
+pub fn sample_torrent(root: &Path) -> (Torrent, Vec<u8>, Vec<u8>) {
+    let a: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+    let b: Vec<u8> = (0..30_000).map(|i| (i / 7) as u8).collect();
+    let torrent = test_torrent(root, &[("a", &a), ("sub/b", &b)]);
+    (torrent, a, b)
+}
@@ -1,23 +1,42 @@
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
 };
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio_util::sync::CancellationToken;
 
-use hashes::Hashes;
-use tracing::{error, info};
+pub use builder::TorrentBuilder;
+pub use extra::Extra;
+pub use hashes::Hashes;
+pub use verify::{PieceStatus, Verification};
+
+mod builder;
+pub mod merkle;
+mod verify;
+use tracing::{error, info, warn};
 
 use crate::{
+    bencode::{self, Bencode, BencodeRef},
     message::Request,
-    tracker::{Peers, TrackerRequest, TrackerResponse},
+    resume::Resume,
+    storage::Storage,
+    tracker::{Peers, Scrape, Tiers, TrackerRequest, TrackerSession, TransferStats},
+    webseed::{self, WebSeed},
     Hash,
 };
 
 pub const BLOCK_MAX: usize = 1 << 14;
 
+/// Number of pieces downloaded between saves of the fast-resume file.
+pub const RESUME_INTERVAL: usize = 16;
+
 pub struct DownloadedPiece {
     number: usize,
     blocks: Vec<u8>,
@@ -25,19 +44,257 @@ pub struct DownloadedPiece {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Torrent {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+
     pub info: Info,
+
+    /// Piece layers of the v2 files larger than a piece (BEP 52), keyed by their pieces root.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+
+    /// HTTP servers mirroring the content (BEP 19).
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+
+    #[serde(skip)]
+    trackers: OnceLock<Arc<Tiers>>,
+
+    /// What every piece of a hybrid torrent hashes to in the v2 half, found when the torrent is
+    /// loaded. Empty for torrents with only one half.
+    #[serde(skip)]
+    v2_pieces: Arc<Vec<Option<V2Piece>>>,
+}
+
+/// `url-list` holds either a single URL or a list of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl UrlList {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            UrlList::One(url) => std::slice::from_ref(url),
+            UrlList::Many(urls) => urls,
+        }
+    }
+}
+
+/// The swarms a torrent can be shared in. Hybrid torrents belong to both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Swarm {
+    V1,
+    V2,
 }
 
 impl Torrent {
     pub async fn new(path: PathBuf) -> anyhow::Result<Self> {
         let data = tokio::fs::read(path).await.context("read torrent file")?;
-        serde_bencode::from_bytes(&data).context("failed deserializing")
+        Self::from_bytes(&data)
+    }
+
+    /// A torrent for an info dictionary obtained elsewhere, such as from the peers of a magnet
+    /// link. Every tracker gets its own tier. There are no piece layers to go with the info, so
+    /// pieces of hybrid torrents are only checked against their SHA-1 hashes.
+    pub fn from_info(info: Info, trackers: Vec<String>) -> Self {
+        Self {
+            announce: trackers.first().cloned().unwrap_or_default(),
+            announce_list: (trackers.len() > 1)
+                .then(|| trackers.into_iter().map(|url| vec![url]).collect()),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info,
+            piece_layers: None,
+            url_list: None,
+            trackers: OnceLock::new(),
+            v2_pieces: Default::default(),
+        }
     }
 
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let value = BencodeRef::new(data).context("failed decoding")?;
+        let mut torrent = Self::deserialize(bencode::Deserializer::new(&value, &mut Vec::new()))
+            .context("failed deserializing")?;
+        torrent.info.raw = value.get("info").map(|info| info.raw().to_vec());
+        torrent.info.claim_keys()?;
+        torrent.v2_pieces = Arc::new(torrent.find_v2_pieces()?);
+        Ok(torrent)
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        bencode::to_bytes(self).context("failed serializing")
+    }
+
+    /// The hash identifying the torrent to trackers and peers. Hybrid torrents use their v1 swarm.
     pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        let info_bencoded = serde_bencode::to_bytes(&self.info).context("re-encoding")?;
-        Ok(*Hash::new(&info_bencoded))
+        let swarm = self.swarms().next().context("torrent has no pieces")?;
+        self.swarm_hash(swarm)
+    }
+
+    pub fn swarms(&self) -> impl Iterator<Item = Swarm> {
+        let v2 = self.info.is_v2();
+        let v1 = !v2 || !self.info.pieces.is_empty();
+        [(v1, Swarm::V1), (v2, Swarm::V2)]
+            .into_iter()
+            .filter_map(|(member, swarm)| member.then_some(swarm))
+    }
+
+    /// The 20 bytes identifying the torrent in `swarm`: the v1 info hash, or the v2 one truncated.
+    pub fn swarm_hash(&self, swarm: Swarm) -> anyhow::Result<[u8; 20]> {
+        match swarm {
+            Swarm::V1 => self.info.hash(),
+            Swarm::V2 => {
+                let hash = self.info.hash_v2()?;
+                Ok(hash[..20].try_into().expect("is length 20"))
+            }
+        }
+    }
+
+    /// The piece layer of a v2 file, checked against the file's pieces root. Files no longer than
+    /// a piece have none.
+    pub fn piece_layer(&self, file: &V2File) -> anyhow::Result<Option<Vec<merkle::Node>>> {
+        let Some(root) = file.pieces_root else {
+            return Ok(None);
+        };
+        if file.length <= self.info.piece_length {
+            return Ok(None);
+        }
+
+        let layer = self
+            .piece_layers
+            .as_ref()
+            .and_then(|layers| layers.get(serde_bytes::Bytes::new(&root.0)))
+            .context("piece layer is missing")?;
+        anyhow::ensure!(
+            layer.len() == file.length.div_ceil(self.info.piece_length) * 32,
+            "piece layer has the wrong length"
+        );
+
+        let layer: Vec<merkle::Node> = layer
+            .chunks_exact(32)
+            .map(|node| node.try_into().expect("is length 32"))
+            .collect();
+        anyhow::ensure!(
+            merkle::layer_root(&layer, self.info.piece_length) == root.0,
+            "piece layer doesn't match the pieces root"
+        );
+
+        Ok(Some(layer))
+    }
+
+    /// Checks a piece of a v2 file against the file's merkle tree. Pieces are counted from the
+    /// start of the file; a file no longer than a piece is checked as a whole.
+    pub fn verify_v2_piece(
+        &self,
+        file: &V2File,
+        piece: usize,
+        data: &[u8],
+    ) -> anyhow::Result<bool> {
+        let piece_length = self.info.piece_length;
+        let expected_length = piece_length.min(file.length.saturating_sub(piece * piece_length));
+        if data.len() != expected_length || expected_length == 0 {
+            return Ok(false);
+        }
+
+        let root = file.pieces_root.context("empty files have no pieces")?;
+        match self.piece_layer(file)? {
+            Some(layer) => Ok(merkle::piece_hash(data, piece_length) == layer[piece]),
+            None => Ok(merkle::file_root(data) == root.0),
+        }
+    }
+
+    /// Checks a piece against its SHA-1 hash and, for hybrid torrents, against the merkle tree of
+    /// the v2 file it falls in.
+    pub fn verify_hybrid_piece(&self, piece: usize, data: &[u8]) -> bool {
+        self.info
+            .pieces
+            .get(piece)
+            .is_some_and(|hash| *Hash::new(data) == *hash)
+            && matches_v2(&self.v2_pieces, piece, data, self.info.piece_length)
+    }
+
+    /// Finds what every piece of a hybrid torrent hashes to in the v2 half, checking each piece
+    /// layer against its pieces root on the way. Hybrid torrents pad every file to a piece
+    /// boundary, so a piece never spans two files.
+    fn find_v2_pieces(&self) -> anyhow::Result<Vec<Option<V2Piece>>> {
+        let Some(tree) = self.info.file_tree.as_ref().filter(|_| self.info.is_v2()) else {
+            return Ok(Vec::new());
+        };
+        if self.info.pieces.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let v2_files: BTreeMap<_, _> = tree.files().into_iter().collect();
+        let piece_length = self.info.piece_length;
+        let mut pieces = vec![None; self.info.pieces.len()];
+        let mut offset: usize = 0;
+
+        for file in self.info.files() {
+            if !file.is_padding() && file.length > 0 {
+                let name = file.path.join("/");
+                let v2_file = v2_files
+                    .get(&file.path)
+                    .with_context(|| format!("{name} isn't in the file tree"))?;
+                anyhow::ensure!(
+                    v2_file.length == file.length,
+                    "{name} has a different length in the file tree"
+                );
+                anyhow::ensure!(
+                    offset.is_multiple_of(piece_length),
+                    "{name} isn't aligned to a piece"
+                );
+                let root = v2_file
+                    .pieces_root
+                    .with_context(|| format!("{name} has no pieces root"))?;
+                let layer = self.piece_layer(v2_file)?;
+
+                for (index, start) in (0..file.length).step_by(piece_length).enumerate() {
+                    let piece = pieces
+                        .get_mut(offset / piece_length + index)
+                        .with_context(|| format!("{name} is past the last piece"))?;
+                    *piece = Some(V2Piece {
+                        length: piece_length.min(file.length - start),
+                        hash: layer.as_ref().map_or(root.0, |layer| layer[index]),
+                        whole_file: layer.is_none(),
+                    });
+                }
+            }
+            offset += file.length;
+        }
+
+        Ok(pieces)
     }
 
     pub fn piece_hashes(&self) -> impl Iterator<Item = String> + '_ {
@@ -45,64 +302,176 @@ impl Torrent {
     }
 
     pub async fn peers(&self) -> anyhow::Result<Peers> {
-        let Keys::SingleFile { length } = self.info.keys;
         let info_hash = self.info_hash()?;
+        let tracker_request = TrackerRequest::new(self.info.length());
 
-        let tracker_request = TrackerRequest::new(length);
+        let response = self
+            .trackers()
+            .announce(&info_hash, &tracker_request)
+            .await?;
 
-        let url_params = serde_urlencoded::to_string(&tracker_request)
-            .context("url-encode tracker parameters")?;
-        let url = format!(
-            "{}?{}&info_hash={}",
-            self.announce,
-            url_params,
-            urlencode(&info_hash)
-        );
-        let tracker_url = reqwest::Url::parse(&url).context("parse tracker announce URL")?;
+        Ok(response.peers)
+    }
 
-        let tracker_response: TrackerResponse = {
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let bytes = response.bytes().await.context("fetch tracker")?;
-            serde_bencode::from_bytes(&bytes).context("parse tracker")?
-        };
+    /// Statistics of the swarm from the first tracker that answers, or `None` if that tracker
+    /// doesn't track the torrent.
+    pub async fn scrape(&self) -> anyhow::Result<Option<Scrape>> {
+        let info_hash = self.info_hash()?;
+        let scrapes = self.trackers().scrape(&[info_hash]).await?;
+
+        scrapes
+            .into_iter()
+            .next()
+            .context("tracker sent no statistics")
+    }
+
+    /// The trackers from `announce-list`, or `announce` if there is none.
+    pub fn trackers(&self) -> &Arc<Tiers> {
+        self.trackers
+            .get_or_init(|| Arc::new(Tiers::new(&self.announce, self.announce_list.as_deref())))
+    }
 
-        Ok(tracker_response.peers)
+    pub fn web_seeds(&self) -> Vec<WebSeed> {
+        self.url_list
+            .iter()
+            .flat_map(|list| list.urls())
+            .filter(|url| !url.is_empty())
+            .map(WebSeed::new)
+            .collect()
     }
 
     pub fn piece_size(&self, piece: usize) -> usize {
         assert!(piece < self.info.pieces.len());
-        let Keys::SingleFile { length } = self.info.keys;
-        let piece_length = self.info.piece_length;
-        piece_length.min(length - piece_length * piece)
+        piece_size(piece, self.info.length(), self.info.piece_length)
     }
 
     pub fn pieces_size(&self, pieces: impl Iterator<Item = usize>) -> usize {
         pieces.map(|piece| self.piece_size(piece)).sum()
     }
 
+    /// Downloads `pieces` into memory, in order.
     pub async fn download_pieces(
         &self,
-        pieces: impl Iterator<Item = usize> + Clone,
+        pieces: impl Iterator<Item = usize>,
     ) -> anyhow::Result<Vec<u8>> {
-        let info_hash = self.info_hash()?;
+        let mut all_pieces = Vec::new();
+        let never = CancellationToken::new();
+        self.fetch_pieces(pieces, self.info.length(), &never, |piece| {
+            all_pieces.push(piece);
+            Ok(())
+        })
+        .await?;
+        all_pieces.sort_by_key(|piece| piece.number);
 
-        let peers = self.peers().await?;
+        Ok(all_pieces
+            .into_iter()
+            .flat_map(|downloaded_piece| downloaded_piece.blocks)
+            .collect())
+    }
+
+    /// Downloads the whole torrent, writing each piece to `storage` as soon as it's verified.
+    pub async fn download(&self, storage: &mut dyn Storage) -> anyhow::Result<()> {
+        let never = CancellationToken::new();
+        self.fetch_pieces(
+            0..self.info.pieces.len(),
+            self.info.length(),
+            &never,
+            |piece| storage.write_block(piece.number, 0, &piece.blocks),
+        )
+        .await?;
+
+        storage.flush()
+    }
 
-        let (tx, rx) = std::sync::mpsc::sync_channel(self.pieces_size(pieces.clone()));
+    /// Downloads the pieces `resume` is missing into `storage`, saving progress every
+    /// [`RESUME_INTERVAL`] pieces so an interrupted download loses little. Cancelling `cancel`
+    /// ends the download early but cleanly, with progress saved and the trackers told.
+    pub async fn download_missing(
+        &self,
+        storage: &mut dyn Storage,
+        resume: &mut Resume,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let missing = resume.missing();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let left = self.pieces_size(missing.iter().copied());
+
+        let mut unsaved = 0;
+        let result = self
+            .fetch_pieces(missing.into_iter(), left, cancel, |piece| {
+                storage.write_block(piece.number, 0, &piece.blocks)?;
+                resume.mark(piece.number);
+                unsaved += 1;
+                if unsaved == RESUME_INTERVAL {
+                    storage.flush()?;
+                    resume.save()?;
+                    unsaved = 0;
+                }
+                Ok(())
+            })
+            .await;
+
+        // Whatever made it to storage is kept, even when the download failed.
+        storage.flush()?;
+        resume.save()?;
+
+        result
+    }
+
+    /// Downloads `pieces` from the swarm and the web seeds, handing each verified piece to
+    /// `on_piece` as it arrives. At most one piece per connection is held in memory at a time.
+    ///
+    /// Trackers are kept up to date for the whole download, with `left` bytes of the content
+    /// missing at the start. Cancelling `cancel` stops the download, and the trackers are told.
+    async fn fetch_pieces(
+        &self,
+        pieces: impl Iterator<Item = usize>,
+        left: usize,
+        cancel: &CancellationToken,
+        mut on_piece: impl FnMut(DownloadedPiece) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // Pieces are numbered and fetched the v1 way, which v2-only torrents don't support: their
+        // files aren't padded to piece boundaries. Hybrid torrents join the v1 swarm and have
+        // every piece checked against both halves.
+        anyhow::ensure!(
+            self.swarms().any(|swarm| swarm == Swarm::V1),
+            "v2-only torrents can't be downloaded yet"
+        );
+        let info_hash = self.swarm_hash(Swarm::V1)?;
+
+        let web_seeds = self.web_seeds();
+        let stats = Arc::new(TransferStats::new(left));
+        let (mut tracker, peers) =
+            match TrackerSession::start(Arc::clone(self.trackers()), info_hash, Arc::clone(&stats))
+                .await
+            {
+                Ok((tracker, peers)) => (Some(tracker), peers),
+                Err(e) if !web_seeds.is_empty() => {
+                    warn!("downloading from web seeds only: {e:#}");
+                    (None, std::iter::empty().collect())
+                }
+                Err(e) => return Err(e),
+            };
 
         let pieces = VecDeque::from_iter(pieces);
+        let npieces = pieces.len();
         let peers_amount = pieces.len().min(peers.len());
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(peers_amount + web_seeds.len() + 1);
         let work_queue = Arc::new(Mutex::new(pieces));
 
-        let mut handles = Vec::with_capacity(peers.len());
+        let mut handles = Vec::with_capacity(peers.len() + web_seeds.len());
 
         let piece_hashes = &self.info.pieces;
-        let Keys::SingleFile { length } = self.info.keys;
+        let length = self.info.length();
         let piece_length = self.info.piece_length;
 
         for peer in peers.iter().take(peers_amount) {
             let work_queue = Arc::clone(&work_queue);
             let piece_hashes = piece_hashes.clone();
+            let v2_pieces = Arc::clone(&self.v2_pieces);
             let tx = tx.clone();
 
             let handle = tokio::spawn(async move {
@@ -159,11 +528,18 @@ impl Torrent {
 
                     let hash = Hash::new(&all_blocks);
                     assert_eq!(*hash, piece_hashes[piece]);
-
-                    if let Err(e) = tx.send(DownloadedPiece {
-                        number: piece,
-                        blocks: all_blocks,
-                    }) {
+                    assert!(
+                        matches_v2(&v2_pieces, piece, &all_blocks, piece_length),
+                        "piece {piece} doesn't match the v2 hashes of the torrent"
+                    );
+
+                    if let Err(e) = tx
+                        .send(DownloadedPiece {
+                            number: piece,
+                            blocks: all_blocks,
+                        })
+                        .await
+                    {
                         error!("{e}");
                     }
 
@@ -174,47 +550,471 @@ impl Torrent {
             handles.push(handle);
         }
 
+        let info = Arc::new(self.info.clone());
+        for seed in web_seeds {
+            let work_queue = Arc::clone(&work_queue);
+            let info = Arc::clone(&info);
+            let v2_pieces = Arc::clone(&self.v2_pieces);
+            let tx = tx.clone();
+
+            let handle = tokio::spawn(async move {
+                let mut failures = 0;
+
+                loop {
+                    let Some(piece) = work_queue.lock().expect("can lock mutex").pop_front() else {
+                        break;
+                    };
+
+                    info!("Downloading piece {piece} from {}", seed.url());
+
+                    let fetched = seed.fetch_piece(&info, piece).await.and_then(|blocks| {
+                        anyhow::ensure!(
+                            matches_v2(&v2_pieces, piece, &blocks, piece_length),
+                            "piece {piece} doesn't match the v2 hashes of the torrent"
+                        );
+                        Ok(blocks)
+                    });
+
+                    match fetched {
+                        Ok(blocks) => {
+                            failures = 0;
+                            if let Err(e) = tx
+                                .send(DownloadedPiece {
+                                    number: piece,
+                                    blocks,
+                                })
+                                .await
+                            {
+                                error!("{e}");
+                            }
+                        }
+                        Err(e) => {
+                            // Leave the piece to the peers and the other seeds in the meantime.
+                            work_queue.lock().expect("can lock mutex").push_back(piece);
+                            failures += 1;
+                            if failures == webseed::MAX_FAILURES {
+                                warn!("giving up on web seed {}: {e:#}", seed.url());
+                                break;
+                            }
+
+                            let wait = webseed::RETRY_WAIT * 2u32.pow(failures - 1);
+                            warn!(
+                                "web seed {} failed, retrying in {wait:?}: {e:#}",
+                                seed.url()
+                            );
+                            tokio::time::sleep(wait).await;
+                        }
+                    }
+                }
+            });
+
+            handles.push(handle);
+        }
+
         drop(tx);
 
-        let pieces_handle = std::thread::spawn(move || rx.into_iter().collect::<Vec<_>>());
+        let mut downloaded = 0;
+        let received = async {
+            while let Some(piece) = rx.recv().await {
+                let size = piece.blocks.len();
+                on_piece(piece)?;
+                stats.add_piece(size);
+                downloaded += 1;
+
+                if let Some(tracker) = &mut tracker {
+                    tracker.complete().await;
+                }
+            }
+
+            anyhow::Ok(())
+        };
+        let result = tokio::select! {
+            result = received => result,
+            _ = cancel.cancelled() => Err(anyhow::anyhow!("download interrupted")),
+        };
+
+        if result.is_err() {
+            for handle in &handles {
+                handle.abort();
+            }
+        }
+        if let Some(tracker) = tracker {
+            tracker.stop().await;
+        }
+        result?;
 
         for handle in handles {
             handle.await?;
         }
 
-        let mut all_pieces = pieces_handle.join().unwrap();
-        all_pieces.sort_by(|a, b| a.number.cmp(&b.number));
-
-        Ok(all_pieces
-            .into_iter()
-            .flat_map(|downloaded_piece| downloaded_piece.blocks)
-            .collect())
-    }
-
-    pub async fn download(&self) -> anyhow::Result<Vec<u8>> {
-        let data = self.download_pieces(0..self.info.pieces.len()).await?;
+        anyhow::ensure!(
+            downloaded == npieces,
+            "{} pieces couldn't be downloaded",
+            npieces - downloaded
+        );
 
-        Ok(data)
+        Ok(())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Info {
     pub name: String,
 
     #[serde(rename = "piece length")]
     pub piece_length: usize,
 
+    /// SHA-1 hashes of the pieces. Empty for v2-only torrents.
+    #[serde(default, skip_serializing_if = "Hashes::is_empty")]
     pub pieces: Hashes,
 
     #[serde(flatten)]
     pub keys: Keys,
+
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+
+    #[serde(flatten)]
+    pub extra: Extra,
+
+    /// The info dictionary exactly as it appeared in the torrent file.
+    #[serde(skip)]
+    raw: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Info {
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let mut info: Self = bencode::from_bytes(data).context("failed deserializing")?;
+        info.raw = Some(BencodeRef::new(data)?.raw().to_vec());
+        info.claim_keys()?;
+        Ok(info)
+    }
+
+    /// Settles which keys of a freshly parsed info dictionary belong to `keys`. Serde hands every
+    /// key `Info` doesn't claim itself to all of its flattened fields, so `extra` starts out with
+    /// the ones `keys` took as well. Fails for dictionaries with none of `length`, `files` or a
+    /// v2 file tree, which `Keys::FileTree` would otherwise take for an empty torrent, and for v2
+    /// piece lengths that aren't a power of two of at least a block.
+    fn claim_keys(&mut self) -> anyhow::Result<()> {
+        if let Keys::FileTree {} = self.keys {
+            anyhow::ensure!(self.is_v2(), "info has no length, files or v2 file tree");
+        }
+        if self.is_v2() {
+            anyhow::ensure!(
+                self.piece_length.is_power_of_two() && self.piece_length >= merkle::BLOCK_SIZE,
+                "v2 piece length {} isn't a power of two of at least 16 KiB",
+                self.piece_length
+            );
+        }
+
+        let Bencode::Dictionary(claimed) = bencode::to_bencode(&self.keys)? else {
+            unreachable!("keys serialize to a dictionary");
+        };
+        self.extra
+            .retain(|key, _| !claimed.contains_key(key.as_slice()));
+
+        Ok(())
+    }
+
+    /// SHA-1 of the bencoded info dictionary. Hashes the bytes the dictionary was parsed from when
+    /// there are any, since re-encoding only reproduces them for canonical input.
+    pub fn hash(&self) -> anyhow::Result<[u8; 20]> {
+        match &self.raw {
+            Some(raw) => Ok(*Hash::new(raw)),
+            None => {
+                let info_bencoded = bencode::to_bytes(self).context("re-encoding")?;
+                Ok(*Hash::new(&info_bencoded))
+            }
+        }
+    }
+
+    /// SHA-256 of the bencoded info dictionary, which identifies v2 torrents.
+    pub fn hash_v2(&self) -> anyhow::Result<[u8; 32]> {
+        match &self.raw {
+            Some(raw) => Ok(merkle::sha256(raw)),
+            None => {
+                let info_bencoded = bencode::to_bytes(self).context("re-encoding")?;
+                Ok(merkle::sha256(&info_bencoded))
+            }
+        }
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    /// Private torrents (BEP 27) may only get peers from their trackers.
+    pub fn is_private(&self) -> bool {
+        self.extra.get(serde_bytes::Bytes::new(b"private")) == Some(&Bencode::Number(1))
+    }
+
+    /// Total length of the torrent's content, across all files.
+    pub fn length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+            Keys::FileTree {} => self.files().iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Every file of the torrent in content order, including the padding files of hybrid
+    /// torrents. A single-file torrent has one file named after the torrent.
+    pub fn files(&self) -> Vec<FileEntry> {
+        match &self.keys {
+            Keys::SingleFile { length } => vec![FileEntry {
+                length: *length,
+                path: vec![self.name.clone()],
+                attr: None,
+            }],
+            Keys::MultiFile { files } => files.clone(),
+            Keys::FileTree {} => self
+                .file_tree
+                .iter()
+                .flat_map(|tree| tree.files())
+                .map(|(path, file)| FileEntry {
+                    length: file.length,
+                    path,
+                    attr: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Keys {
-    SingleFile { length: usize },
+    SingleFile {
+        length: usize,
+    },
+    MultiFile {
+        files: Vec<FileEntry>,
+    },
+    /// v2-only torrents list their files in the file tree alone. Being empty, this matches any
+    /// dictionary, so [`Info`] checks that it really is a v2 torrent.
+    FileTree {},
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: usize,
+    pub path: Vec<String>,
+    /// File attributes (BEP 47), such as `p` for the padding files of hybrid torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl FileEntry {
+    /// Padding files only align the next file to a piece boundary and are never written out.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+
+    /// The file's path relative to the torrent's directory. Fails for components that would
+    /// escape it, such as `..` or absolute paths.
+    pub fn relative_path(&self) -> anyhow::Result<PathBuf> {
+        anyhow::ensure!(!self.path.is_empty(), "file has an empty path");
+
+        let mut relative_path = PathBuf::new();
+        for component in &self.path {
+            anyhow::ensure!(
+                !component.is_empty()
+                    && component != "."
+                    && component != ".."
+                    && !component.contains(['/', '\\']),
+                "invalid path component {component:?}"
+            );
+            relative_path.push(component);
+        }
+
+        Ok(relative_path)
+    }
+}
+
+/// The files of a v2 torrent (BEP 52), as a tree of directories ordered by name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FileTree(pub BTreeMap<String, FileNode>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FileNode {
+    /// Files are dictionaries with a single empty key.
+    File {
+        #[serde(rename = "")]
+        file: V2File,
+    },
+    Directory(BTreeMap<String, FileNode>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct V2File {
+    pub length: usize,
+
+    /// Root of the merkle tree over the file's blocks. Empty files have none.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<PiecesRoot>,
+}
+
+/// What a piece of a hybrid torrent hashes to in the v2 half.
+#[derive(Debug, Clone)]
+struct V2Piece {
+    /// Bytes of the piece in its file. The rest of the piece is padding.
+    length: usize,
+    /// The piece's node in the file's piece layer, or the pieces root of a file no longer than a
+    /// piece.
+    hash: merkle::Node,
+    whole_file: bool,
+}
+
+/// Whether `data` matches the v2 hash of `piece`. Pieces without one always do.
+fn matches_v2(
+    v2_pieces: &[Option<V2Piece>],
+    piece: usize,
+    data: &[u8],
+    piece_length: usize,
+) -> bool {
+    let Some(Some(v2_piece)) = v2_pieces.get(piece) else {
+        return true;
+    };
+    let Some(data) = data.get(..v2_piece.length) else {
+        return false;
+    };
+
+    let hash = if v2_piece.whole_file {
+        merkle::file_root(data)
+    } else {
+        merkle::piece_hash(data, piece_length)
+    };
+    hash == v2_piece.hash
+}
+
+impl FileTree {
+    /// Every file with its path, in tree order.
+    pub fn files(&self) -> Vec<(Vec<String>, &V2File)> {
+        fn walk<'a>(
+            directory: &'a BTreeMap<String, FileNode>,
+            path: &mut Vec<String>,
+            files: &mut Vec<(Vec<String>, &'a V2File)>,
+        ) {
+            for (name, node) in directory {
+                path.push(name.clone());
+                match node {
+                    FileNode::File { file } => files.push((path.clone(), file)),
+                    FileNode::Directory(directory) => walk(directory, path, files),
+                }
+                path.pop();
+            }
+        }
+
+        let mut files = Vec::new();
+        walk(&self.0, &mut Vec::new(), &mut files);
+        files
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiecesRoot(pub merkle::Node);
+
+impl Serialize for PiecesRoot {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for PiecesRoot {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        let root = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| serde::de::Error::invalid_length(bytes.len(), &"32 bytes"))?;
+        Ok(PiecesRoot(root))
+    }
+}
+
+mod extra {
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::ops::{Deref, DerefMut};
+
+    use serde::{
+        de::{self, Visitor},
+        Deserialize, Deserializer, Serialize,
+    };
+    use serde_bytes::ByteBuf;
+
+    use crate::bencode::Bencode;
+
+    /// Info dictionary keys not modelled by [`super::Info`], such as `private` or `source`. They
+    /// are kept so that re-encoding the dictionary doesn't change its hash.
+    #[derive(Debug, Clone, Default, Serialize)]
+    pub struct Extra(BTreeMap<ByteBuf, Bencode>);
+
+    impl Deref for Extra {
+        type Target = BTreeMap<ByteBuf, Bencode>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl DerefMut for Extra {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.0
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Extra {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_map(ExtraVisitor)
+        }
+    }
+
+    struct ExtraVisitor;
+
+    impl<'de> Visitor<'de> for ExtraVisitor {
+        type Value = Extra;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a dictionary")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::MapAccess<'de>,
+        {
+            let mut extra = BTreeMap::new();
+
+            // This includes the keys `Keys` takes, which `Info` drops once parsed.
+            while let Some(key) = map.next_key::<ByteBuf>()? {
+                extra.insert(key, map.next_value()?);
+            }
+
+            Ok(Extra(extra))
+        }
+    }
 }
 
 mod hashes {
@@ -227,9 +1027,21 @@ mod hashes {
         Deserialize, Deserializer,
     };
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Default)]
     pub struct Hashes(Vec<[u8; 20]>);
 
+    impl Hashes {
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    impl FromIterator<[u8; 20]> for Hashes {
+        fn from_iter<I: IntoIterator<Item = [u8; 20]>>(iter: I) -> Self {
+            Self(iter.into_iter().collect())
+        }
+    }
+
     impl Deref for Hashes {
         type Target = Vec<[u8; 20]>;
 
@@ -270,7 +1082,7 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
 
@@ -283,17 +1095,245 @@ mod hashes {
     }
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(t.len() * 3);
+pub fn piece_size(piece: usize, length: usize, piece_length: usize) -> usize {
+    piece_length.min(length - piece_length * piece)
+}
 
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode([byte]));
-    }
+/// Runs `work` on pieces `0..npieces` on every core and returns its results in piece order.
+/// Each thread starts from its own state made by `init`, like a buffer or open files.
+fn map_pieces<S, T: Send>(
+    npieces: usize,
+    init: impl Fn() -> anyhow::Result<S> + Sync,
+    work: impl Fn(&mut S, usize) -> anyhow::Result<T> + Sync,
+) -> anyhow::Result<Vec<T>> {
+    let next_piece = AtomicUsize::new(0);
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(npieces.max(1));
+
+    let mut results: Vec<Option<T>> = (0..npieces).map(|_| None).collect();
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> anyhow::Result<Vec<(usize, T)>> {
+                    let mut state = init()?;
+                    let mut done = Vec::new();
+
+                    loop {
+                        let piece = next_piece.fetch_add(1, Ordering::Relaxed);
+                        if piece >= npieces {
+                            break;
+                        }
+                        done.push((piece, work(&mut state, piece)?));
+                    }
+
+                    Ok(done)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let done = handle.join().expect("piece thread doesn't panic")?;
+            for (piece, result) in done {
+                results[piece] = Some(result);
+            }
+        }
 
-    encoded
+        anyhow::Ok(())
+    })?;
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("every piece is done"))
+        .collect())
 }
 
-pub fn piece_size(piece: usize, length: usize, piece_length: usize) -> usize {
-    piece_length.min(length - piece_length * piece)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_info_as_parsed() {
+        let data = b"d8:announce3:url4:infod6:lengthi7e4:name1:a12:piece lengthi4e\
+                     6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:abcee";
+        let info = &data[22..data.len() - 1];
+
+        let torrent = Torrent::from_bytes(data).unwrap();
+        assert_eq!(torrent.info_hash().unwrap(), *Hash::new(info));
+        assert_eq!(torrent.info.extra.len(), 2);
+        assert!(torrent.info.is_private());
+        assert_eq!(bencode::to_bytes(&torrent).unwrap(), data);
+    }
+
+    #[test]
+    fn keeps_keys_only_once() {
+        let data = b"d8:announce3:url4:infod5:filesld6:lengthi7e4:pathl1:beee4:name1:a\
+                     12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(data).unwrap();
+        assert!(torrent.info.extra.is_empty());
+        assert_eq!(bencode::to_bytes(&torrent).unwrap(), data);
+
+        // A key the matching variant of `Keys` doesn't take is kept like any other.
+        let data = b"d8:announce3:url4:infod5:filesle6:lengthi7e4:name1:a\
+                     12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(data).unwrap();
+        assert!(matches!(torrent.info.keys, Keys::SingleFile { length: 7 }));
+        assert_eq!(torrent.info.extra.keys().collect::<Vec<_>>(), [b"files"]);
+        assert_eq!(bencode::to_bytes(&torrent).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_info_without_files() {
+        let data = b"d8:announce3:url4:infod4:name1:a12:piece lengthi4e6:pieces0:ee";
+        assert!(Torrent::from_bytes(data).is_err());
+        assert!(Info::from_bytes(&data[22..data.len() - 1]).is_err());
+    }
+
+    fn dictionary<const N: usize>(entries: [(&str, Bencode); N]) -> Bencode {
+        entries.into_iter().collect()
+    }
+
+    #[test]
+    fn parses_hybrid_torrents() {
+        let piece_length = 2 * merkle::BLOCK_SIZE;
+        let content: Vec<u8> = (0..3 * merkle::BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let root = merkle::file_root(&content);
+        let layer = merkle::piece_layer(&content, piece_length);
+
+        let info = dictionary([
+            (
+                "file tree",
+                dictionary([(
+                    "a",
+                    dictionary([(
+                        "",
+                        dictionary([
+                            ("length", Bencode::from(content.len() as isize)),
+                            ("pieces root", Bencode::from(&root[..])),
+                        ]),
+                    )]),
+                )]),
+            ),
+            ("length", Bencode::from(content.len() as isize)),
+            ("meta version", Bencode::from(2)),
+            ("name", Bencode::from("a")),
+            ("piece length", Bencode::from(piece_length as isize)),
+            (
+                "pieces",
+                Bencode::from(
+                    content
+                        .chunks(piece_length)
+                        .flat_map(|piece| *Hash::new(piece))
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+        ]);
+        let data = dictionary([
+            ("announce", Bencode::from("url")),
+            ("info", info.clone()),
+            (
+                "piece layers",
+                [(root.to_vec(), Bencode::from(layer.concat()))]
+                    .into_iter()
+                    .collect(),
+            ),
+        ])
+        .encode();
+
+        let torrent = Torrent::from_bytes(&data).unwrap();
+        assert_eq!(torrent.to_bytes().unwrap(), data);
+        assert_eq!(torrent.swarms().collect::<Vec<_>>(), [Swarm::V1, Swarm::V2]);
+        assert_eq!(torrent.info_hash().unwrap(), *Hash::new(info.encode()));
+        assert_eq!(
+            torrent.swarm_hash(Swarm::V2).unwrap(),
+            merkle::sha256(info.encode())[..20]
+        );
+        assert_eq!(torrent.info.length(), content.len());
+
+        let files = torrent.info.file_tree.as_ref().unwrap().files();
+        let (path, file) = &files[0];
+        assert_eq!(*path, ["a"]);
+        assert_eq!(torrent.piece_layer(file).unwrap().unwrap(), layer);
+        assert!(torrent
+            .verify_v2_piece(file, 1, &content[piece_length..])
+            .unwrap());
+        assert!(!torrent
+            .verify_v2_piece(file, 0, &content[piece_length..])
+            .unwrap());
+        assert!(!torrent.verify_v2_piece(file, 0, &content[..10]).unwrap());
+        assert!(torrent.verify_hybrid_piece(1, &content[piece_length..]));
+        assert!(!torrent.verify_hybrid_piece(0, &content[piece_length..]));
+
+        // Piece layers are checked against the pieces roots as the torrent loads.
+        let mut bad_layer = layer.concat();
+        bad_layer[0] ^= 1;
+        let data = dictionary([
+            ("announce", Bencode::from("url")),
+            ("info", info),
+            (
+                "piece layers",
+                [(root.to_vec(), Bencode::from(bad_layer))]
+                    .into_iter()
+                    .collect(),
+            ),
+        ])
+        .encode();
+        assert!(Torrent::from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn parses_v2_only_torrents() {
+        let file = |length: isize, root: Option<merkle::Node>| {
+            let mut file = vec![("length", Bencode::from(length))];
+            file.extend(root.map(|root| ("pieces root", Bencode::from(&root[..]))));
+            dictionary([("", file.into_iter().collect())])
+        };
+        let v2_info = |piece_length: isize| {
+            dictionary([
+                (
+                    "file tree",
+                    dictionary([("a", file(3, Some(merkle::file_root(b"abc"))))]),
+                ),
+                ("meta version", Bencode::from(2)),
+                ("name", Bencode::from("v2")),
+                ("piece length", Bencode::from(piece_length)),
+            ])
+            .encode()
+        };
+        assert!(Info::from_bytes(&v2_info(1 << 15)).is_ok());
+        assert!(Info::from_bytes(&v2_info(3 << 14)).is_err());
+        assert!(Info::from_bytes(&v2_info(1 << 13)).is_err());
+
+        let info = dictionary([
+            (
+                "file tree",
+                dictionary([
+                    (
+                        "dir",
+                        dictionary([("b", file(3, Some(merkle::file_root(b"abc"))))]),
+                    ),
+                    ("empty", file(0, None)),
+                ]),
+            ),
+            ("meta version", Bencode::from(2)),
+            ("name", Bencode::from("v2")),
+            ("piece length", Bencode::from(1 << 14)),
+        ]);
+        let data = dictionary([("announce", Bencode::from("url")), ("info", info)]).encode();
+
+        let torrent = Torrent::from_bytes(&data).unwrap();
+        assert!(matches!(torrent.info.keys, Keys::FileTree {}));
+        assert_eq!(torrent.swarms().collect::<Vec<_>>(), [Swarm::V2]);
+        assert_eq!(torrent.info.length(), 3);
+
+        let paths: Vec<_> = torrent.info.files().into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, [vec!["dir", "b"], vec!["empty"]]);
+
+        let files = torrent.info.file_tree.as_ref().unwrap().files();
+        assert!(torrent.verify_v2_piece(files[0].1, 0, b"abc").unwrap());
+        assert!(!torrent.verify_v2_piece(files[0].1, 0, b"abd").unwrap());
+        assert_eq!(torrent.to_bytes().unwrap(), data);
+    }
 }
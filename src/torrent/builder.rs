@@ -0,0 +1,330 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde_bytes::ByteBuf;
+
+use super::{map_pieces, Extra, FileEntry, Info, Keys, Torrent, UrlList};
+use crate::{bencode::Bencode, Hash};
+
+const MIN_PIECE_LENGTH: usize = 1 << 14;
+const MAX_PIECE_LENGTH: usize = 1 << 24;
+/// Piece count [`TorrentBuilder`] aims for when picking a piece length itself.
+const TARGET_PIECES: usize = 1500;
+
+/// Creates a torrent for a file or a directory on disk.
+pub struct TorrentBuilder {
+    path: PathBuf,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    piece_length: Option<usize>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    private: bool,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            announce: None,
+            announce_list: Vec::new(),
+            web_seeds: Vec::new(),
+            piece_length: None,
+            comment: None,
+            created_by: None,
+            private: false,
+        }
+    }
+
+    /// Sets the main tracker. Defaults to the first tracker of the first tier.
+    pub fn announce(mut self, url: impl Into<String>) -> Self {
+        self.announce = Some(url.into());
+        self
+    }
+
+    /// Adds a tier of trackers to the announce list (BEP 12).
+    pub fn announce_tier(mut self, urls: Vec<String>) -> Self {
+        self.announce_list.push(urls);
+        self
+    }
+
+    /// Adds an HTTP server mirroring the content (BEP 19).
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    /// Must be a power of two of at least 16 KiB. Picked from the content size if not set.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Walks the content and hashes it, which reads every byte of it. Pieces are hashed on all
+    /// available cores.
+    pub fn build(self) -> anyhow::Result<Torrent> {
+        let announce = self
+            .announce
+            .or_else(|| self.announce_list.first()?.first().cloned())
+            .context("torrent needs a tracker")?;
+
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("path has no valid file name")?
+            .to_string();
+
+        let metadata = std::fs::metadata(&self.path).context("read content metadata")?;
+        let (keys, files) = if metadata.is_dir() {
+            let mut files = Vec::new();
+            walk(&self.path, &mut Vec::new(), &mut files)?;
+            anyhow::ensure!(!files.is_empty(), "directory has no files");
+
+            let entries = files.iter().map(|(_, entry)| entry.clone()).collect();
+            let paths = files
+                .into_iter()
+                .map(|(path, entry)| (path, entry.length))
+                .collect();
+            (Keys::MultiFile { files: entries }, paths)
+        } else {
+            let length = metadata.len() as usize;
+            (
+                Keys::SingleFile { length },
+                vec![(self.path.clone(), length)],
+            )
+        };
+
+        let length = files.iter().map(|(_, length)| length).sum();
+        let piece_length = match self.piece_length {
+            Some(piece_length) => {
+                anyhow::ensure!(
+                    piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH,
+                    "piece length must be a power of two of at least {MIN_PIECE_LENGTH}"
+                );
+                piece_length
+            }
+            None => pick_piece_length(length),
+        };
+
+        let pieces = hash_pieces(&files, length, piece_length)?;
+
+        let mut extra = Extra::default();
+        if self.private {
+            extra.insert(ByteBuf::from("private"), Bencode::Number(1));
+        }
+
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .ok();
+
+        Ok(Torrent {
+            announce,
+            announce_list: (!self.announce_list.is_empty()).then_some(self.announce_list),
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date,
+            info: Info {
+                name,
+                piece_length,
+                pieces: pieces.into_iter().collect(),
+                keys,
+                meta_version: None,
+                file_tree: None,
+                extra,
+                raw: None,
+            },
+            piece_layers: None,
+            url_list: (!self.web_seeds.is_empty()).then_some(UrlList::Many(self.web_seeds)),
+            trackers: Default::default(),
+            v2_pieces: Default::default(),
+        })
+    }
+}
+
+/// Collects the files under `dir` in a stable order, along with their path components relative
+/// to the torrent's root directory. Symlinks are skipped, so links that loop or lead out of the
+/// tree can't pull anything in.
+fn walk(
+    dir: &Path,
+    components: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, FileEntry)>,
+) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("read directory {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .context("read directory entry")?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("file name {name:?} is not valid UTF-8"))?;
+        let metadata = std::fs::symlink_metadata(&path).context("read file metadata")?;
+        if metadata.is_symlink() {
+            continue;
+        }
+
+        components.push(name);
+        if metadata.is_dir() {
+            walk(&path, components, files)?;
+        } else {
+            let entry = FileEntry {
+                length: metadata.len() as usize,
+                path: components.clone(),
+                attr: None,
+            };
+            files.push((path, entry));
+        }
+        components.pop();
+    }
+
+    Ok(())
+}
+
+fn pick_piece_length(length: usize) -> usize {
+    length
+        .div_ceil(TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+fn hash_pieces(
+    files: &[(PathBuf, usize)],
+    length: usize,
+    piece_length: usize,
+) -> anyhow::Result<Vec<[u8; 20]>> {
+    map_pieces(
+        length.div_ceil(piece_length),
+        || Ok(Vec::with_capacity(piece_length)),
+        |buffer, piece| {
+            let start = piece * piece_length;
+            let end = length.min(start + piece_length);
+            buffer.clear();
+            read_range(files, start, end, buffer)?;
+            Ok(*Hash::new(buffer))
+        },
+    )
+}
+
+/// Reads the bytes in `start..end` of the content, which may span several files.
+fn read_range(
+    files: &[(PathBuf, usize)],
+    start: usize,
+    end: usize,
+    buffer: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    let mut file_start = 0;
+
+    for (path, length) in files {
+        let file_end = file_start + length;
+
+        if file_end > start && file_start < end {
+            let from = start.max(file_start) - file_start;
+            let to = end.min(file_end) - file_start;
+
+            let mut file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+            file.seek(SeekFrom::Start(from as u64))?;
+            file.take((to - from) as u64)
+                .read_to_end(buffer)
+                .with_context(|| format!("read {}", path.display()))?;
+        }
+
+        file_start = file_end;
+    }
+
+    anyhow::ensure!(buffer.len() == end - start, "content changed while hashing");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_multi_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let a = vec![1u8; 20_000];
+        let b = vec![2u8; 30_000];
+        std::fs::write(root.join("sub/b"), &b).unwrap();
+        std::fs::write(root.join("a"), &a).unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .announce("http://tracker/announce")
+            .piece_length(1 << 14)
+            .comment("test")
+            .private(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(torrent.info.name, "content");
+        assert_eq!(torrent.info.length(), 50_000);
+        assert!(torrent.info.is_private());
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("expected a multi-file torrent");
+        };
+        assert_eq!(files[0].path, ["a"]);
+        assert_eq!(files[1].path, ["sub", "b"]);
+
+        let content = [a, b].concat();
+        let expected: Vec<_> = content.chunks(1 << 14).map(|c| *Hash::new(c)).collect();
+        assert_eq!(*torrent.info.pieces, expected);
+
+        let parsed = Torrent::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.info_hash().unwrap(), torrent.info_hash().unwrap());
+        assert_eq!(parsed.comment.as_deref(), Some("test"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), [1; 100]).unwrap();
+        std::fs::write(dir.path().join("outside"), [2; 100]).unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside"), root.join("outside")).unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .announce("http://tracker/announce")
+            .build()
+            .unwrap();
+        let paths: Vec<_> = torrent.info.files().into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, [["a"]]);
+    }
+
+    #[test]
+    fn picks_piece_length() {
+        assert_eq!(pick_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(pick_piece_length(1 << 30), 1 << 20);
+        assert_eq!(pick_piece_length(1 << 40), MAX_PIECE_LENGTH);
+    }
+}
@@ -0,0 +1,109 @@
+use sha2::{Digest, Sha256};
+
+/// Leaves of a v2 merkle tree are the hashes of blocks of this size.
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+/// A SHA-256 node of a merkle tree.
+pub type Node = [u8; 32];
+
+pub fn sha256(data: impl AsRef<[u8]>) -> Node {
+    Sha256::digest(data).into()
+}
+
+fn parent(left: &Node, right: &Node) -> Node {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a tree `width` leaves wide, a power of two, whose leaves past `leaves` are `pad`.
+fn root(leaves: &[Node], width: usize, pad: Node) -> Node {
+    debug_assert!(width.is_power_of_two() && width >= leaves.len());
+
+    let mut layer = leaves.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| parent(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = parent(&pad, &pad);
+        width /= 2;
+    }
+
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Hashes of the blocks of `data`. Only the last block may be shorter than [`BLOCK_SIZE`].
+pub fn block_hashes(data: &[u8]) -> Vec<Node> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+/// Root of the subtree covering a single piece. The leaves of a file's last piece that are past
+/// its end are zero.
+pub fn piece_hash(data: &[u8], piece_length: usize) -> Node {
+    root(&block_hashes(data), piece_length / BLOCK_SIZE, [0; 32])
+}
+
+/// The piece layer of a file: the hash of every piece of it, in order.
+pub fn piece_layer(data: &[u8], piece_length: usize) -> Vec<Node> {
+    data.chunks(piece_length)
+        .map(|piece| piece_hash(piece, piece_length))
+        .collect()
+}
+
+/// The `pieces root` of a file with the given contents.
+pub fn file_root(data: &[u8]) -> Node {
+    let blocks = block_hashes(data);
+    root(&blocks, blocks.len().next_power_of_two(), [0; 32])
+}
+
+/// The `pieces root` a piece layer hashes up to. Pieces past the end of the file are hashes of
+/// zeroed leaves.
+pub fn layer_root(layer: &[Node], piece_length: usize) -> Node {
+    let pad = piece_hash(&[], piece_length);
+    root(layer, layer.len().next_power_of_two(), pad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_trees() {
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let blocks = block_hashes(&data);
+        assert_eq!(blocks.len(), 4);
+
+        // Two blocks per piece, so the piece layer sits right above the leaves.
+        let layer = piece_layer(&data, 2 * BLOCK_SIZE);
+        assert_eq!(
+            layer,
+            [
+                parent(&blocks[0], &blocks[1]),
+                parent(&blocks[2], &blocks[3])
+            ]
+        );
+
+        let root = parent(&layer[0], &layer[1]);
+        assert_eq!(file_root(&data), root);
+        assert_eq!(layer_root(&layer, 2 * BLOCK_SIZE), root);
+
+        // A third piece pads the layer with the hash of an all-zero piece.
+        let data = vec![1; 4 * BLOCK_SIZE + 1];
+        let layer = piece_layer(&data, 2 * BLOCK_SIZE);
+        let pad = parent(&[0; 32], &[0; 32]);
+        assert_eq!(layer[2], parent(&sha256([1]), &[0; 32]));
+        assert_eq!(
+            layer_root(&layer, 2 * BLOCK_SIZE),
+            parent(&parent(&layer[0], &layer[1]), &parent(&layer[2], &pad))
+        );
+        assert_eq!(layer_root(&layer, 2 * BLOCK_SIZE), file_root(&data));
+
+        // Small files hash their blocks alone, without padding up to a whole piece.
+        assert_eq!(file_root(b"abc"), sha256(b"abc"));
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::Path;
+
+use super::{map_pieces, piece_size, FileEntry, Swarm, Torrent};
+use crate::storage::{FileStorage, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    /// On disk and matching its hash.
+    Good,
+    /// On disk but not matching its hash.
+    Bad,
+    /// In a file that's missing or too short.
+    Missing,
+}
+
+/// How the data on disk compares with a torrent, piece by piece.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub pieces: Vec<PieceStatus>,
+    /// Every file that isn't padding, with the number of its bytes in good pieces.
+    pub files: Vec<(FileEntry, usize)>,
+}
+
+impl Verification {
+    pub fn count(&self, status: PieceStatus) -> usize {
+        self.pieces.iter().filter(|&&piece| piece == status).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|&piece| piece == PieceStatus::Good)
+    }
+}
+
+impl Torrent {
+    /// Checks the data under `path` against the piece hashes, hashing pieces on every core.
+    /// Nothing on disk is created or changed.
+    pub fn verify(&self, path: &Path) -> anyhow::Result<Verification> {
+        anyhow::ensure!(
+            self.swarms().any(|swarm| swarm == Swarm::V1),
+            "only torrents with v1 pieces can be verified"
+        );
+
+        let pieces = map_pieces(
+            self.info.pieces.len(),
+            || FileStorage::open(&self.info, path),
+            |storage, piece| {
+                Ok(if !storage.is_stored(piece, 0, self.piece_size(piece)) {
+                    PieceStatus::Missing
+                } else if storage.verify_piece(&self.info, piece)? {
+                    PieceStatus::Good
+                } else {
+                    PieceStatus::Bad
+                })
+            },
+        )?;
+
+        let length = self.info.length();
+        let piece_length = self.info.piece_length;
+        let mut files = Vec::new();
+        let mut offset = 0;
+
+        for file in self.info.files() {
+            let end = offset + file.length;
+            if !file.is_padding() {
+                let good = (offset / piece_length..end.div_ceil(piece_length))
+                    .filter(|&piece| pieces[piece] == PieceStatus::Good)
+                    .map(|piece| {
+                        let start = piece * piece_length;
+                        let stop = start + piece_size(piece, length, piece_length);
+                        stop.min(end) - start.max(offset)
+                    })
+                    .sum();
+                files.push((file, good));
+            }
+            offset = end;
+        }
+
+        Ok(Verification { pieces, files })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_torrent;
+
+    #[test]
+    fn verifies_data_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        let torrent = test_torrent(
+            &root,
+            &[
+                ("a", &[1; 20_000]),
+                ("b", &[2; 30_000]),
+                ("c", &[3; 10_000]),
+            ],
+        );
+
+        let verification = torrent.verify(&root).unwrap();
+        assert!(verification.is_complete());
+        assert_eq!(verification.count(PieceStatus::Good), 4);
+
+        // Pieces 0 and 1 span a, and piece 3 the end of b and c.
+        std::fs::write(root.join("a"), vec![0; 20_000]).unwrap();
+        std::fs::remove_file(root.join("c")).unwrap();
+        let verification = torrent.verify(&root).unwrap();
+        assert!(!verification.is_complete());
+        assert_eq!(
+            verification.pieces,
+            [
+                PieceStatus::Bad,
+                PieceStatus::Bad,
+                PieceStatus::Good,
+                PieceStatus::Missing
+            ]
+        );
+        let good: Vec<_> = verification.files.iter().map(|(_, good)| *good).collect();
+        assert_eq!(good, [0, 1 << 14, 0]);
+        assert!(!root.join("c").exists());
+    }
+}
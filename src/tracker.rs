@@ -1,11 +1,302 @@
+use std::collections::BTreeMap;
 use std::fmt;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Mutex;
+use std::time::Duration;
 
+use anyhow::Context;
+use rand::seq::SliceRandom;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
+use tracing::warn;
 
+use crate::bencode;
 use crate::peer::*;
 
+pub use session::{TrackerSession, TransferStats};
+pub use udp::UdpTracker;
+
+mod session;
+mod udp;
+
+/// Fewest seconds between regular announces when the tracker doesn't set a `min interval`, so a
+/// tracker answering with an interval of 0 isn't asked again straight away.
+const MIN_INTERVAL: usize = 60;
+
+/// The trackers of a torrent grouped in tiers, as described in BEP 12.
+///
+/// Each tier is shuffled once when created. Tiers are tried in order, and within a tier the
+/// first tracker that answers is moved to its front so it is asked first next time.
+#[derive(Debug)]
+pub struct Tiers {
+    tiers: Mutex<Vec<Vec<String>>>,
+    /// The `tracker id` each tracker asked to get back with later announces, by URL.
+    tracker_ids: Mutex<BTreeMap<String, String>>,
+}
+
+impl Tiers {
+    /// Uses `announce_list` when it has any tracker and falls back to `announce` otherwise.
+    pub fn new(announce: &str, announce_list: Option<&[Vec<String>]>) -> Self {
+        let mut tiers: Vec<Vec<String>> = announce_list
+            .unwrap_or_default()
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+
+        if tiers.is_empty() && !announce.is_empty() {
+            tiers.push(vec![announce.to_string()]);
+        }
+
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+
+        Self::ordered(tiers)
+    }
+
+    /// Keeps the trackers in the order given.
+    fn ordered(tiers: Vec<Vec<String>>) -> Self {
+        Self {
+            tiers: Mutex::new(tiers),
+            tracker_ids: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The trackers in the order they will be tried.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.tiers
+            .lock()
+            .expect("tiers lock isn't poisoned")
+            .clone()
+    }
+
+    /// Moves `url` to the front of its tier.
+    pub fn promote(&self, tier: usize, url: &str) {
+        let mut tiers = self.tiers.lock().expect("tiers lock isn't poisoned");
+        if let Some(trackers) = tiers.get_mut(tier) {
+            if let Some(position) = trackers.iter().position(|tracker| tracker == url) {
+                trackers[..=position].rotate_right(1);
+            }
+        }
+    }
+
+    /// Announces to the first tracker that answers in every tier. Returns the answer of the
+    /// first tier to answer, with the peers of every other answer merged in. Fails only if no
+    /// tracker answered at all.
+    pub async fn announce(
+        &self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let tiers = self.tiers();
+        anyhow::ensure!(!tiers.is_empty(), "torrent has no trackers");
+
+        let mut response = None::<TrackerResponse>;
+        let mut last_error = None;
+
+        for (tier, trackers) in tiers.iter().enumerate() {
+            for url in trackers {
+                let mut request = request.clone();
+                request.trackerid = self
+                    .tracker_ids
+                    .lock()
+                    .expect("tracker ids lock isn't poisoned")
+                    .get(url)
+                    .cloned();
+
+                match announce(url, info_hash, &request).await {
+                    Ok(answer) => {
+                        self.promote(tier, url);
+                        if let Some(tracker_id) = &answer.tracker_id {
+                            self.tracker_ids
+                                .lock()
+                                .expect("tracker ids lock isn't poisoned")
+                                .insert(url.clone(), tracker_id.clone());
+                        }
+                        match &mut response {
+                            Some(response) => response.peers.merge(answer.peers),
+                            None => response = Some(answer),
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("tracker {url} failed: {e:#}");
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        match (response, last_error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e.context("every tracker failed")),
+            (None, None) => unreachable!("tiers are never empty"),
+        }
+    }
+
+    /// Scrapes the first tracker that answers, in the order announces try them.
+    pub async fn scrape(&self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<Option<Scrape>>> {
+        let tiers = self.tiers();
+        anyhow::ensure!(!tiers.is_empty(), "torrent has no trackers");
+
+        let mut last_error = None;
+        for url in tiers.iter().flatten() {
+            match scrape(url, info_hashes).await {
+                Ok(scrapes) => return Ok(scrapes),
+                Err(e) => {
+                    warn!("scrape {url} failed: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error
+            .expect("tiers are never empty")
+            .context("every tracker failed"))
+    }
+}
+
+/// Sends `request` to a single tracker, over UDP or HTTP depending on the scheme of its URL.
+pub async fn announce(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> anyhow::Result<TrackerResponse> {
+    let mut response = if url.starts_with("udp://") {
+        UdpTracker::resolve(url)
+            .await?
+            .announce(info_hash, request)
+            .await?
+    } else {
+        http_announce(url, info_hash, request).await?
+    };
+    response.peers.merge(response.peers6.clone());
+
+    Ok(response)
+}
+
+async fn http_announce(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> anyhow::Result<TrackerResponse> {
+    let url_params =
+        serde_urlencoded::to_string(request).context("url-encode tracker parameters")?;
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{url}{separator}{url_params}&info_hash={}",
+        urlencode(info_hash)
+    );
+    let tracker_url = reqwest::Url::parse(&url).context("parse tracker announce URL")?;
+
+    let response = reqwest::get(tracker_url).await.context("query tracker")?;
+    let bytes = response.bytes().await.context("fetch tracker")?;
+    check_failure(&bytes)?;
+    let response: TrackerResponse = bencode::from_bytes(&bytes).context("parse tracker")?;
+
+    if let Some(warning) = &response.warning_message {
+        warn!("tracker {url} warns: {warning}");
+    }
+
+    Ok(response)
+}
+
+/// Asks a single tracker for the statistics of every torrent in `info_hashes` at once, in the
+/// same order. Torrents the tracker doesn't track get `None`. UDP trackers can't tell, and
+/// answer for every torrent.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<Option<Scrape>>> {
+    if url.starts_with("udp://") {
+        let scrapes = UdpTracker::resolve(url).await?.scrape(info_hashes).await?;
+        return Ok(scrapes.into_iter().map(Some).collect());
+    }
+
+    let scrape_url = scrape_url(url).with_context(|| format!("{url} doesn't support scrape"))?;
+    let mut url = scrape_url;
+    for (i, info_hash) in info_hashes.iter().enumerate() {
+        let separator = if i == 0 && !url.contains('?') {
+            '?'
+        } else {
+            '&'
+        };
+        url.push(separator);
+        url.push_str("info_hash=");
+        url.push_str(&urlencode(info_hash));
+    }
+    let tracker_url = reqwest::Url::parse(&url).context("parse tracker scrape URL")?;
+
+    let response = reqwest::get(tracker_url).await.context("query tracker")?;
+    let bytes = response.bytes().await.context("fetch tracker")?;
+    check_failure(&bytes)?;
+    let response: ScrapeResponse = bencode::from_bytes(&bytes).context("parse tracker")?;
+
+    // Torrents the tracker doesn't know are left out of the answer.
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            response
+                .files
+                .get(serde_bytes::Bytes::new(info_hash))
+                .copied()
+        })
+        .collect())
+}
+
+/// The scrape URL of an HTTP tracker, by the convention of replacing `announce` at the start of
+/// the last path segment of its announce URL with `scrape`. Trackers whose URL doesn't follow it
+/// don't support scrape.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+    let (base, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+
+    let mut url = format!("{base}/scrape{rest}");
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+
+    Some(url)
+}
+
+/// A tracker's refusal of a request, with the reason it gave.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("tracker refused the request: {reason}")]
+pub struct TrackerFailure {
+    pub reason: String,
+}
+
+/// Turns a response with a `failure reason`, which has none of the other keys, into an error.
+fn check_failure(bytes: &[u8]) -> anyhow::Result<()> {
+    #[derive(Deserialize)]
+    struct Failure {
+        #[serde(rename = "failure reason", default)]
+        failure_reason: Option<String>,
+    }
+
+    let failure: Failure = bencode::from_bytes(bytes).context("parse tracker")?;
+    match failure.failure_reason {
+        Some(reason) => Err(TrackerFailure { reason }.into()),
+        None => Ok(()),
+    }
+}
+
+fn urlencode(t: &[u8; 20]) -> String {
+    let mut encoded = String::with_capacity(t.len() * 3);
+
+    for &byte in t {
+        encoded.push('%');
+        encoded.push_str(&hex::encode([byte]));
+    }
+
+    encoded
+}
+
 /// Note: info_hash field is not included
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
@@ -15,6 +306,11 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    /// The `tracker id` the tracker sent before.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
 }
 
 impl TrackerRequest {
@@ -26,31 +322,165 @@ impl TrackerRequest {
             downloaded: 0,
             left,
             compact: 1,
+            event: None,
+            trackerid: None,
         }
     }
 }
 
+/// Why an announce is sent, when it isn't a regular one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first announce of a download.
+    Started,
+    /// The download has just finished.
+    Completed,
+    /// The client is shutting down.
+    Stopped,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
+    /// Seconds to wait between regular announces.
     pub interval: usize,
+
+    /// Seconds announces must never come sooner than.
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+
+    /// Something the tracker has to say about a request it did handle.
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+
+    /// To be sent back with every later announce to the same tracker.
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<String>,
+
+    /// Number of seeders in the swarm.
+    #[serde(default)]
+    pub complete: Option<usize>,
+
+    /// Number of leechers in the swarm.
+    #[serde(default)]
+    pub incomplete: Option<usize>,
+
+    /// Compact or dictionary-model peers. Announces add those of `peers6` in.
+    #[serde(default)]
     pub peers: Peers,
+
+    /// Compact IPv6 peers (BEP 7).
+    #[serde(default, deserialize_with = "deserialize_peers6")]
+    pub peers6: Peers,
+}
+
+impl TrackerResponse {
+    /// How long to wait before the next regular announce: the interval, but no less than the
+    /// `min interval`, or [`MIN_INTERVAL`] without one.
+    pub fn next_announce(&self) -> Duration {
+        let floor = self.min_interval.unwrap_or(MIN_INTERVAL).max(1);
+        Duration::from_secs(self.interval.max(floor) as u64)
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Peers(Vec<SocketAddrV4>);
+/// What a tracker knows of the swarm of a torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct Scrape {
+    /// Peers with the whole torrent.
+    pub complete: usize,
+    /// Times the torrent was downloaded to completion.
+    pub downloaded: usize,
+    /// Peers still downloading.
+    pub incomplete: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScrapeResponse {
+    files: BTreeMap<ByteBuf, Scrape>,
+}
+
+/// Parses peers packed as 4 or 16 bytes of address and 2 of port.
+fn compact_peers(data: &[u8], ipv6: bool) -> anyhow::Result<Vec<SocketAddr>> {
+    let size = if ipv6 { 18 } else { 6 };
+    anyhow::ensure!(
+        data.len().is_multiple_of(size),
+        "peers are {} bytes long",
+        data.len()
+    );
+
+    Ok(data
+        .chunks_exact(size)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(size - 2);
+            let port = u16::from_be_bytes([port[0], port[1]]);
+            if ipv6 {
+                let ip: [u8; 16] = ip.try_into().expect("is 16 bytes");
+                SocketAddrV6::new(ip.into(), port, 0, 0).into()
+            } else {
+                let ip: [u8; 4] = ip.try_into().expect("is 4 bytes");
+                SocketAddrV4::new(ip.into(), port).into()
+            }
+        })
+        .collect())
+}
+
+fn deserialize_peers6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let peers = ByteBuf::deserialize(deserializer)?;
+
+    Ok(compact_peers(&peers, true)
+        .map_err(de::Error::custom)?
+        .into_iter()
+        .collect())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Peers {
+    addrs: Vec<SocketAddr>,
+    /// Peer ids from dictionary-model peer lists, which come with them.
+    ids: BTreeMap<SocketAddr, [u8; 20]>,
+}
 
 impl Peers {
     pub fn iter(&self) -> impl Iterator<Item = Peer<NoId, NoSession, NoPieces, NotReady>> + '_ {
-        self.0.iter().map(|addr| Peer::new(*addr))
+        self.addrs.iter().map(|addr| Peer::new(*addr))
     }
 
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.addrs.len()
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// The id the tracker gave for the peer at `addr`, if it gave one.
+    pub fn peer_id(&self, addr: &SocketAddr) -> Option<&[u8; 20]> {
+        self.ids.get(addr)
+    }
+
+    /// Adds the peers of `other` that aren't already known.
+    pub fn merge(&mut self, other: Peers) {
+        for peer in other.addrs {
+            if !self.addrs.contains(&peer) {
+                self.addrs.push(peer);
+            }
+        }
+        for (peer, id) in other.ids {
+            self.ids.entry(peer).or_insert(id);
+        }
+    }
+}
+
+impl FromIterator<SocketAddr> for Peers {
+    fn from_iter<T: IntoIterator<Item = SocketAddr>>(iter: T) -> Self {
+        Self {
+            addrs: iter.into_iter().collect(),
+            ids: BTreeMap::new(),
+        }
+    }
 }
 
 impl Serialize for Peers {
@@ -58,11 +488,14 @@ impl Serialize for Peers {
     where
         S: Serializer,
     {
-        let mut single_slice = Vec::with_capacity(self.0.len() * 6);
+        let mut single_slice = Vec::with_capacity(self.addrs.len() * 6);
 
-        for peer in &self.0 {
-            single_slice.extend(peer.ip().octets());
-            single_slice.extend(peer.port().to_be_bytes());
+        // The compact model only has room for IPv4 peers; IPv6 ones go in `peers6`.
+        for peer in &self.addrs {
+            if let SocketAddr::V4(peer) = peer {
+                single_slice.extend(peer.ip().octets());
+                single_slice.extend(peer.port().to_be_bytes());
+            }
         }
 
         serializer.serialize_bytes(&single_slice)
@@ -84,27 +517,266 @@ impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a list of peers composed of 4 bytes for IP and 2 bytes for port")
+        formatter.write_str(
+            "a list of peers composed of 4 bytes for IP and 2 bytes for port, \
+             or a list of dictionaries with an ip and a port",
+        )
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        #[derive(Deserialize)]
+        struct DictionaryPeer {
+            #[serde(rename = "peer id", default)]
+            peer_id: Option<ByteBuf>,
+            ip: String,
+            port: u16,
+        }
+
+        let mut peers = Peers::default();
+        while let Some(peer) = seq.next_element::<DictionaryPeer>()? {
+            // Peers given by hostname can't be connected to.
+            let Ok(ip) = peer.ip.parse::<IpAddr>() else {
+                continue;
+            };
+            let addr = SocketAddr::new(ip, peer.port);
+
+            if let Some(id) = peer
+                .peer_id
+                .and_then(|id| <[u8; 20]>::try_from(&id[..]).ok())
+            {
+                peers.ids.insert(addr, id);
+            }
+            peers.merge(std::iter::once(addr).collect());
+        }
+
+        Ok(peers)
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if v.len() % 6 != 0 {
-            return Err(E::custom(format!("length is {}", v.len())));
-        }
+        Ok(compact_peers(v, false)
+            .map_err(E::custom)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves `body` as the response to every HTTP request and returns the announce URL.
+    async fn tracker(body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+        format!("http://{addr}/announce")
+    }
+
+    async fn dead_tracker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/announce", listener.local_addr().unwrap())
+    }
 
-        let peers = v
-            .chunks_exact(6)
-            .map(|slice| {
-                SocketAddrV4::new(
-                    Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]),
-                    u16::from_be_bytes([slice[4], slice[5]]),
-                )
+    #[test]
+    fn parses_rich_responses() {
+        let response: TrackerResponse = bencode::from_bytes(
+            b"d8:completei4e10:incompletei2e8:intervali1800e12:min intervali900e\
+              5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip3:::14:porti1eed2:ip9:127.0.0.24:porti80eee\
+              6:peers618:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1\
+              10:tracker id3:abc15:warning message4:slowe",
+        )
+        .unwrap();
+
+        assert_eq!(response.next_announce(), Duration::from_secs(1800));
+        assert_eq!((response.complete, response.incomplete), (Some(4), Some(2)));
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert_eq!(
+            response.peers.addrs,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:1".parse().unwrap(),
+                "127.0.0.2:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            response.peers.peer_id(&"127.0.0.1:6881".parse().unwrap()),
+            Some(&[b'a'; 20])
+        );
+        assert_eq!(response.peers6.addrs, ["[::1]:6881".parse().unwrap()]);
+
+        // Trackers can't ask for announces without a pause.
+        let response: TrackerResponse = bencode::from_bytes(b"d8:intervali0ee").unwrap();
+        assert_eq!(response.next_announce(), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn announces_ipv6_peers() {
+        let url = tracker(
+            b"d8:intervali1800e5:peers6:\x7f\0\0\x01\x1a\xe1\
+              6:peers618:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e",
+        )
+        .await;
+
+        let response = announce(&url, &[0; 20], &TrackerRequest::new(0))
+            .await
+            .unwrap();
+        let peers: Vec<_> = response.peers.iter().map(|peer| *peer.addr()).collect();
+        assert_eq!(
+            peers,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6881".parse().unwrap()
+            ]
+        );
+        // Only IPv4 peers fit the compact model.
+        assert_eq!(
+            bencode::to_bytes(&response.peers).unwrap(),
+            b"6:\x7f\0\0\x01\x1a\xe1"
+        );
+    }
+
+    #[tokio::test]
+    async fn surfaces_failures() {
+        let url = tracker(b"d14:failure reason17:torrent not founde").await;
+
+        let e = announce(&url, &[0; 20], &TrackerRequest::new(0))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<TrackerFailure>(),
+            Some(&TrackerFailure {
+                reason: "torrent not found".to_string()
             })
-            .collect();
+        );
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let peers = compact_peers(&[10, 0, 0, 1, 0, 80], false).unwrap();
+        assert_eq!(peers, ["10.0.0.1:80".parse().unwrap()]);
+
+        let mut data = [0; 18];
+        data[15] = 1;
+        data[17] = 80;
+        let peers = compact_peers(&data, true).unwrap();
+        assert_eq!(peers, ["[::1]:80".parse().unwrap()]);
+
+        assert!(compact_peers(&[0; 7], false).is_err());
+    }
+
+    #[test]
+    fn derives_scrape_urls() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=a/b").as_deref(),
+            Some("http://example.com/x/scrape.php?passkey=a/b")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn scrapes_several_torrents() {
+        let url = tracker(
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10eeee",
+        )
+        .await;
+
+        let scrapes = scrape(&url, &[[b'a'; 20], [b'b'; 20]]).await.unwrap();
+        assert_eq!(
+            scrapes,
+            [
+                Some(Scrape {
+                    complete: 5,
+                    downloaded: 50,
+                    incomplete: 10
+                }),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn shuffles_within_tiers() {
+        let list = vec![
+            vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            vec![],
+            vec!["d".to_string()],
+        ];
+        let tiers = Tiers::new("x", Some(&list)).tiers();
+        assert_eq!(tiers.len(), 2);
+        let mut first = tiers[0].clone();
+        first.sort();
+        assert_eq!(first, ["a", "b", "c"]);
+        assert_eq!(tiers[1], ["d"]);
+
+        assert_eq!(Tiers::new("x", Some(&[vec![]])).tiers(), [["x"]]);
+        assert!(Tiers::new("", None).tiers().is_empty());
+    }
+
+    #[test]
+    fn promotes_tracker() {
+        let tiers = Tiers::ordered(vec![vec![
+            "a".to_string(),
+            "b".to_string(),
+            "c".to_string(),
+        ]]);
+        tiers.promote(0, "c");
+        assert_eq!(tiers.tiers(), [["c", "a", "b"]]);
+        tiers.promote(0, "missing");
+        tiers.promote(1, "a");
+        assert_eq!(tiers.tiers(), [["c", "a", "b"]]);
+    }
+
+    #[tokio::test]
+    async fn announces_across_tiers() {
+        let dead = dead_tracker().await;
+        let first =
+            tracker(b"d8:intervali60e5:peers12:\x7f\0\0\x01\x1a\xe1\x7f\0\0\x02\x1a\xe1e").await;
+        let second =
+            tracker(b"d8:intervali60e5:peers12:\x7f\0\0\x02\x1a\xe1\x7f\0\0\x03\x1a\xe1e").await;
+
+        let tiers = Tiers::ordered(vec![vec![dead.clone(), first.clone()], vec![second]]);
+        let response = tiers
+            .announce(&[0; 20], &TrackerRequest::new(0))
+            .await
+            .unwrap();
+
+        let addrs: Vec<_> = response.peers.addrs.iter().map(|peer| peer.ip()).collect();
+        assert_eq!(
+            addrs,
+            [[127, 0, 0, 1], [127, 0, 0, 2], [127, 0, 0, 3]].map(IpAddr::from)
+        );
+        assert_eq!(tiers.tiers()[0], [first, dead.clone()]);
 
-        Ok(Peers(peers))
+        let tiers = Tiers::ordered(vec![vec![dead]]);
+        assert!(tiers
+            .announce(&[0; 20], &TrackerRequest::new(0))
+            .await
+            .is_err());
     }
 }
@@ -0,0 +1,230 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use super::{Event, Peers, Tiers, TrackerRequest};
+
+/// How long the last announces of a session may take. They are best effort, and a dead tracker
+/// mustn't hold up the end of a download.
+const FINAL_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest wait between re-announces while no tracker answers.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/// Totals of a download, as reported to trackers. The client doesn't serve pieces to other
+/// peers, so it always reports nothing uploaded.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    downloaded: AtomicUsize,
+    left: AtomicUsize,
+}
+
+impl TransferStats {
+    /// Stats of a download with `left` bytes still to get.
+    pub fn new(left: usize) -> Self {
+        Self {
+            left: AtomicUsize::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> usize {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    /// Counts a verified piece of `bytes` as downloaded, and no longer left.
+    pub fn add_piece(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        // Saturates in case a piece is downloaded twice.
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    /// An announce with the current totals.
+    pub fn request(&self, event: Option<Event>) -> TrackerRequest {
+        TrackerRequest {
+            downloaded: self.downloaded(),
+            event,
+            ..TrackerRequest::new(self.left())
+        }
+    }
+}
+
+/// The announces of one download: `started` first, regular announces as often as the trackers
+/// ask for them, `completed` once nothing is left and `stopped` at the end.
+#[derive(Debug)]
+pub struct TrackerSession {
+    tiers: Arc<Tiers>,
+    info_hash: [u8; 20],
+    stats: Arc<TransferStats>,
+    reannounce: JoinHandle<()>,
+    completed: bool,
+}
+
+impl TrackerSession {
+    /// Sends `started` and keeps announcing in the background. Returns the session along with
+    /// the peers the trackers sent.
+    pub async fn start(
+        tiers: Arc<Tiers>,
+        info_hash: [u8; 20],
+        stats: Arc<TransferStats>,
+    ) -> anyhow::Result<(Self, Peers)> {
+        let response = tiers
+            .announce(&info_hash, &stats.request(Some(Event::Started)))
+            .await?;
+
+        let reannounce = tokio::spawn(reannounce(
+            Arc::clone(&tiers),
+            info_hash,
+            Arc::clone(&stats),
+            response.next_announce(),
+        ));
+
+        let session = Self {
+            tiers,
+            info_hash,
+            stats,
+            reannounce,
+            completed: false,
+        };
+
+        Ok((session, response.peers))
+    }
+
+    /// Sends `completed` the first time it's called after the last byte was downloaded.
+    pub async fn complete(&mut self) {
+        if self.completed || self.stats.left() > 0 {
+            return;
+        }
+        self.completed = true;
+        self.final_announce(Event::Completed).await;
+    }
+
+    /// Stops the regular announces and sends `stopped`.
+    pub async fn stop(self) {
+        self.reannounce.abort();
+        self.final_announce(Event::Stopped).await;
+    }
+
+    async fn final_announce(&self, event: Event) {
+        let request = self.stats.request(Some(event));
+        let announce = self.tiers.announce(&self.info_hash, &request);
+
+        match tokio::time::timeout(FINAL_ANNOUNCE_TIMEOUT, announce).await {
+            Ok(Ok(_)) => info!("announced {event:?}"),
+            Ok(Err(e)) => warn!("announce {event:?}: {e:#}"),
+            Err(_) => warn!("announce {event:?}: trackers didn't answer in time"),
+        }
+    }
+}
+
+impl Drop for TrackerSession {
+    fn drop(&mut self) {
+        self.reannounce.abort();
+    }
+}
+
+/// Announces every time the last answer says to, doubling the wait each time no tracker
+/// answers.
+async fn reannounce(
+    tiers: Arc<Tiers>,
+    info_hash: [u8; 20],
+    stats: Arc<TransferStats>,
+    wait: Duration,
+) {
+    let mut wait = wait;
+
+    loop {
+        tokio::time::sleep(wait).await;
+
+        match tiers.announce(&info_hash, &stats.request(None)).await {
+            Ok(response) => {
+                info!("re-announced, trackers know {} peers", response.peers.len());
+                wait = response.next_announce();
+            }
+            Err(e) => {
+                wait = (wait * 2).min(MAX_RETRY_WAIT);
+                warn!("re-announce: {e:#}, retrying in {}s", wait.as_secs());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A tracker asking for announces every second, which records the query strings it gets.
+    async fn tracker() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let queries = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = Arc::clone(&queries);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let n = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                let query = request.split(' ').nth(1).unwrap_or_default().to_string();
+                seen.lock().unwrap().push(query);
+
+                let body = b"d8:intervali1e12:min intervali1e5:peers6:\x7f\0\0\x01\x1a\xe1e";
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+
+        (format!("http://{addr}/announce"), queries)
+    }
+
+    #[tokio::test]
+    async fn runs_announce_lifecycle() {
+        let (url, queries) = tracker().await;
+        let tiers = Arc::new(Tiers::new(&url, None));
+        let stats = Arc::new(TransferStats::new(100));
+
+        let (mut session, peers) = TrackerSession::start(tiers, [0; 20], Arc::clone(&stats))
+            .await
+            .unwrap();
+        assert_eq!(peers.len(), 1);
+
+        stats.add_piece(60);
+        session.complete().await;
+        assert_eq!(queries.lock().unwrap().len(), 1);
+
+        // The regular announce comes after the interval of a second.
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        stats.add_piece(40);
+        session.complete().await;
+        session.complete().await;
+        session.stop().await;
+
+        let queries = queries.lock().unwrap();
+        let has = |query: &str, part: &str| query.split(['?', '&']).any(|p| p == part);
+        assert_eq!(queries.len(), 4);
+        assert!(has(&queries[0], "event=started") && has(&queries[0], "left=100"));
+        assert!(!queries[1].contains("event=") && has(&queries[1], "downloaded=60"));
+        assert!(has(&queries[2], "event=completed") && has(&queries[2], "left=0"));
+        assert!(has(&queries[3], "event=stopped") && has(&queries[3], "downloaded=100"));
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use super::{compact_peers, Event, Scrape, TrackerFailure, TrackerRequest, TrackerResponse};
+
+/// Magic number that stands in for the connection ID of connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+/// How long a connection ID may be used after the tracker hands it out.
+const CONNECTION_TTL: Duration = Duration::from_secs(60);
+/// The first timeout, which doubles on every retransmission.
+const TIMEOUT: Duration = Duration::from_secs(15);
+const RETRIES: u32 = 8;
+/// Trackers answer at most this many info hashes in one scrape.
+const MAX_SCRAPE: usize = 74;
+
+/// Connection IDs by tracker, with when they were handed out, shared by every request.
+static CONNECTIONS: Mutex<BTreeMap<SocketAddr, (u64, Instant)>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+enum Action {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+    Error = 3,
+}
+
+/// A tracker spoken to over UDP (BEP 15).
+#[derive(Debug, Clone)]
+pub struct UdpTracker {
+    addr: SocketAddr,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl UdpTracker {
+    /// Looks up the host of a `udp://host:port/...` URL.
+    pub async fn resolve(url: &str) -> anyhow::Result<Self> {
+        let parsed = reqwest::Url::parse(url).context("parse tracker URL")?;
+        anyhow::ensure!(parsed.scheme() == "udp", "{url} isn't a UDP tracker");
+        let host = parsed.host_str().context("tracker URL has no host")?;
+        let port = parsed.port().context("tracker URL has no port")?;
+        // Brackets are kept around IPv6 hosts in URLs.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let addr = tokio::net::lookup_host((host, port))
+            .await
+            .with_context(|| format!("resolve {host}"))?
+            .next()
+            .with_context(|| format!("{host} has no address"))?;
+
+        Ok(Self::new(addr))
+    }
+
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            timeout: TIMEOUT,
+            retries: RETRIES,
+        }
+    }
+
+    /// Waits `timeout` for the first answer, doubling it on each of up to `retries`
+    /// retransmissions.
+    pub fn timeout(mut self, timeout: Duration, retries: u32) -> Self {
+        self.timeout = timeout;
+        self.retries = retries;
+        self
+    }
+
+    pub async fn announce(
+        &self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let peer_id = request.peer_id.as_bytes();
+        anyhow::ensure!(peer_id.len() == 20, "peer id isn't 20 bytes long");
+
+        let mut body = Vec::with_capacity(82);
+        body.extend(info_hash);
+        body.extend(peer_id);
+        body.extend((request.downloaded as u64).to_be_bytes());
+        body.extend((request.left as u64).to_be_bytes());
+        body.extend((request.uploaded as u64).to_be_bytes());
+        body.extend(
+            match request.event {
+                None => 0u32,
+                Some(Event::Completed) => 1,
+                Some(Event::Started) => 2,
+                Some(Event::Stopped) => 3,
+            }
+            .to_be_bytes(),
+        );
+        // The tracker takes our address from the packet.
+        body.extend(0u32.to_be_bytes());
+        body.extend(rand::random::<u32>().to_be_bytes());
+        // As many peers as the tracker likes.
+        body.extend((-1i32).to_be_bytes());
+        body.extend(request.port.to_be_bytes());
+
+        let response = self.request(Action::Announce, &body).await?;
+        anyhow::ensure!(response.len() >= 12, "announce response is too short");
+
+        let field = |i: usize| {
+            u32::from_be_bytes(response[i..i + 4].try_into().expect("is 4 bytes")) as usize
+        };
+        // Peers come in the address family of the tracker.
+        let (peers6, peers): (Vec<_>, Vec<_>) =
+            compact_peers(&response[12..], self.addr.is_ipv6())?
+                .into_iter()
+                .partition(SocketAddr::is_ipv6);
+
+        Ok(TrackerResponse {
+            interval: field(0),
+            min_interval: None,
+            warning_message: None,
+            tracker_id: None,
+            complete: Some(field(8)),
+            incomplete: Some(field(4)),
+            peers: peers.into_iter().collect(),
+            peers6: peers6.into_iter().collect(),
+        })
+    }
+
+    /// Asks for the statistics of every torrent in `info_hashes`, in as many requests as it
+    /// takes.
+    pub async fn scrape(&self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<Scrape>> {
+        let mut scrapes = Vec::with_capacity(info_hashes.len());
+
+        for chunk in info_hashes.chunks(MAX_SCRAPE) {
+            let response = self
+                .request(Action::Scrape, chunk.concat().as_slice())
+                .await?;
+            anyhow::ensure!(
+                response.len() >= chunk.len() * 12,
+                "scrape response is too short"
+            );
+
+            scrapes.extend(response.chunks_exact(12).take(chunk.len()).map(|stats| {
+                let field = |i: usize| {
+                    u32::from_be_bytes(stats[i..i + 4].try_into().expect("is 4 bytes")) as usize
+                };
+                Scrape {
+                    complete: field(0),
+                    downloaded: field(4),
+                    incomplete: field(8),
+                }
+            }));
+        }
+
+        Ok(scrapes)
+    }
+
+    /// Sends a request with a valid connection ID, retransmitting it until it's answered, and
+    /// returns the body of the answer. Connecting and requesting share one schedule of timeouts,
+    /// doubling from the first on every attempt.
+    async fn request(&self, action: Action, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let socket = self.socket().await?;
+        let mut attempt = 0;
+
+        while attempt <= self.retries {
+            let timeout = self.timeout * 2u32.pow(attempt);
+            attempt += 1;
+
+            let (connection_id, cached) = match self.cached_connection() {
+                Some(connection_id) => (connection_id, true),
+                None => match self.connect(&socket, timeout).await? {
+                    Some(connection_id) => (connection_id, false),
+                    None => {
+                        debug!("tracker {} timed out after {timeout:?}", self.addr);
+                        continue;
+                    }
+                },
+            };
+
+            match exchange(&socket, connection_id, action, body, timeout).await {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => {
+                    debug!("tracker {} timed out after {timeout:?}", self.addr);
+                    self.forget_connection();
+                }
+                // The tracker may have forgotten the connection before it expired here, so a
+                // cached one is replaced once without counting as an attempt.
+                Err(e) if cached && e.is::<TrackerFailure>() => {
+                    debug!("tracker {} refused its connection ID: {e:#}", self.addr);
+                    self.forget_connection();
+                    attempt -= 1;
+                }
+                Err(e) => {
+                    self.forget_connection();
+                    return Err(e);
+                }
+            }
+        }
+
+        anyhow::bail!("tracker {} doesn't answer", self.addr)
+    }
+
+    /// The connection ID the tracker handed out last, unless it has expired.
+    fn cached_connection(&self) -> Option<u64> {
+        CONNECTIONS
+            .lock()
+            .expect("connections lock isn't poisoned")
+            .get(&self.addr)
+            .filter(|(_, obtained)| obtained.elapsed() < CONNECTION_TTL)
+            .map(|&(connection_id, _)| connection_id)
+    }
+
+    fn forget_connection(&self) {
+        CONNECTIONS
+            .lock()
+            .expect("connections lock isn't poisoned")
+            .remove(&self.addr);
+    }
+
+    /// Asks for a new connection ID and caches it. `None` if the tracker doesn't answer within
+    /// `timeout`.
+    async fn connect(&self, socket: &UdpSocket, timeout: Duration) -> anyhow::Result<Option<u64>> {
+        let Some(response) = exchange(socket, PROTOCOL_ID, Action::Connect, &[], timeout).await?
+        else {
+            return Ok(None);
+        };
+
+        let connection_id = u64::from_be_bytes(
+            response
+                .get(..8)
+                .context("connect response is too short")?
+                .try_into()
+                .expect("is 8 bytes"),
+        );
+        CONNECTIONS
+            .lock()
+            .expect("connections lock isn't poisoned")
+            .insert(self.addr, (connection_id, Instant::now()));
+
+        Ok(Some(connection_id))
+    }
+
+    async fn socket(&self) -> anyhow::Result<UdpSocket> {
+        let local: SocketAddr = if self.addr.is_ipv6() {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await.context("bind UDP socket")?;
+        socket
+            .connect(self.addr)
+            .await
+            .with_context(|| format!("connect to {}", self.addr))?;
+
+        Ok(socket)
+    }
+}
+
+/// Sends `action` with a new transaction ID and waits `timeout` for the answer to it, skipping
+/// answers to other transactions. Returns the body of the answer, or `None` on timeout.
+async fn exchange(
+    socket: &UdpSocket,
+    connection_id: u64,
+    action: Action,
+    body: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let transaction_id: u32 = rand::random();
+
+    let mut packet = Vec::with_capacity(16 + body.len());
+    packet.extend(connection_id.to_be_bytes());
+    packet.extend((action as u32).to_be_bytes());
+    packet.extend(transaction_id.to_be_bytes());
+    packet.extend(body);
+    socket.send(&packet).await.context("send to tracker")?;
+
+    let receive = async {
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            let n = socket
+                .recv(&mut buffer)
+                .await
+                .context("receive from tracker")?;
+            if n < 8 || buffer[4..8] != transaction_id.to_be_bytes() {
+                continue;
+            }
+
+            let answer = u32::from_be_bytes(buffer[0..4].try_into().expect("is 4 bytes"));
+            if answer == Action::Error as u32 {
+                let reason = String::from_utf8_lossy(&buffer[8..n]).into_owned();
+                return Err(TrackerFailure { reason }.into());
+            }
+            anyhow::ensure!(
+                answer == action as u32,
+                "tracker answered action {answer} to {action:?}"
+            );
+
+            return Ok(buffer[8..n].to_vec());
+        }
+    };
+
+    match tokio::time::timeout(timeout, receive).await {
+        Ok(response) => response.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// A tracker that hands out connection ID 7 and refuses any other, ignores the first
+    /// announce it gets, and then answers with two peers. Returns its address and the count of
+    /// connect requests.
+    async fn tracker() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let connects = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&connects);
+        tokio::spawn(async move {
+            let mut buffer = [0; 2048];
+            let mut announces = 0;
+            while let Ok((n, from)) = socket.recv_from(&mut buffer).await {
+                let request = &buffer[..n];
+                let connection_id = u64::from_be_bytes(request[0..8].try_into().unwrap());
+                let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+                let mut response = request[8..16].to_vec();
+
+                match action {
+                    0 => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        response.extend(7u64.to_be_bytes());
+                    }
+                    _ if connection_id != 7 => {
+                        response[..4].copy_from_slice(&3u32.to_be_bytes());
+                        response.extend(b"unknown connection ID");
+                    }
+                    1 => {
+                        assert_eq!(n, 98);
+                        announces += 1;
+                        if announces == 1 {
+                            continue;
+                        }
+                        response.extend([0, 0, 0, 60, 0, 0, 0, 1, 0, 0, 0, 1]);
+                        response.extend([127, 0, 0, 1, 0x1a, 0xe1, 127, 0, 0, 2, 0x1a, 0xe1]);
+                    }
+                    2 => {
+                        for (i, _) in request[16..].chunks(20).enumerate() {
+                            response.extend([0, 0, 0, i as u8, 0, 0, 0, 5, 0, 0, 0, 2]);
+                        }
+                    }
+                    _ => unreachable!("client sent action {action}"),
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        (addr, connects)
+    }
+
+    #[tokio::test]
+    async fn announces_and_scrapes() {
+        let (addr, connects) = tracker().await;
+        let tracker = UdpTracker::resolve(&format!("udp://{addr}/announce"))
+            .await
+            .unwrap()
+            .timeout(Duration::from_millis(50), 3);
+
+        let response = tracker
+            .announce(&[1; 20], &TrackerRequest::new(100))
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 60);
+        let peers: Vec<_> = response.peers.iter().map(|peer| *peer.addr()).collect();
+        assert_eq!(
+            peers,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "127.0.0.2:6881".parse().unwrap()
+            ]
+        );
+
+        let scrapes = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            scrapes[1],
+            Scrape {
+                complete: 1,
+                downloaded: 5,
+                incomplete: 2
+            }
+        );
+
+        // The announce that timed out dropped the first connection ID, and every request after
+        // it reused the second.
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn replaces_refused_connection_ids() {
+        let (addr, connects) = tracker().await;
+        let tracker = UdpTracker::new(addr).timeout(Duration::from_millis(50), 0);
+        CONNECTIONS
+            .lock()
+            .unwrap()
+            .insert(addr, (8, Instant::now()));
+
+        let scrapes = tracker.scrape(&[[1; 20]]).await.unwrap();
+        assert_eq!(scrapes[0].downloaded, 5);
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+        assert_eq!(tracker.cached_connection(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_silent_trackers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker =
+            UdpTracker::new(socket.local_addr().unwrap()).timeout(Duration::from_millis(10), 2);
+
+        let started = Instant::now();
+        assert!(tracker.scrape(&[[0; 20]]).await.is_err());
+        // Waited 10 + 20 + 40 ms.
+        assert!(started.elapsed() >= Duration::from_millis(70));
+    }
+}
@@ -0,0 +1,335 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::{header, StatusCode};
+
+use crate::{
+    torrent::{piece_size, FileEntry, Info, Keys},
+    Hash,
+};
+
+/// Failures in a row after which a web seed is left out of the rest of a download.
+pub const MAX_FAILURES: u32 = 5;
+
+/// How long to wait after the first failure of a web seed, doubling with every further one in
+/// a row.
+pub const RETRY_WAIT: Duration = Duration::from_millis(500);
+
+/// An HTTP server that mirrors the content of a torrent (BEP 19).
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebSeed {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Where the server keeps `file`. A seed URL that ends in `/` names a directory holding the
+    /// torrent, while any other URL names the file itself for single-file torrents.
+    pub fn file_url(&self, info: &Info, file: &FileEntry) -> String {
+        if matches!(info.keys, Keys::SingleFile { .. }) && !self.url.ends_with('/') {
+            return self.url.clone();
+        }
+
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url.push_str(&encode_segment(&info.name));
+        if !matches!(info.keys, Keys::SingleFile { .. }) {
+            for component in &file.path {
+                url.push('/');
+                url.push_str(&encode_segment(component));
+            }
+        }
+
+        url
+    }
+
+    /// Downloads a piece and checks it against its hash.
+    pub async fn fetch_piece(&self, info: &Info, piece: usize) -> anyhow::Result<Vec<u8>> {
+        let start = piece * info.piece_length;
+        let length = piece_size(piece, info.length(), info.piece_length);
+
+        let data = self.fetch(info, start, start + length).await?;
+        anyhow::ensure!(
+            *Hash::new(&data) == info.pieces[piece],
+            "piece {piece} from {} has the wrong hash",
+            self.url
+        );
+
+        Ok(data)
+    }
+
+    /// Downloads the bytes in `start..end` of the content, with one range request per file
+    /// they span. Padding files are all zeros and aren't requested, and neither are empty files.
+    pub async fn fetch(&self, info: &Info, start: usize, end: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(end - start);
+        let mut file_start = 0;
+
+        for file in info.files() {
+            let file_end = file_start + file.length;
+
+            if file.length > 0 && file_end > start && file_start < end {
+                let from = start.max(file_start) - file_start;
+                let to = end.min(file_end) - file_start;
+
+                if file.is_padding() {
+                    data.resize(data.len() + to - from, 0);
+                } else {
+                    let url = self.file_url(info, &file);
+                    let range = self.fetch_range(&url, from, to, file.length).await?;
+                    data.extend_from_slice(&range);
+                }
+            }
+
+            file_start = file_end;
+        }
+
+        anyhow::ensure!(
+            data.len() == end - start,
+            "content is shorter than the torrent"
+        );
+
+        Ok(data)
+    }
+
+    async fn fetch_range(
+        &self,
+        url: &str,
+        from: usize,
+        to: usize,
+        file_length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .header(header::RANGE, format!("bytes={from}-{}", to - 1))
+            .send()
+            .await
+            .with_context(|| format!("request {url}"))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .await
+            .with_context(|| format!("read {url}"))?;
+
+        match status {
+            StatusCode::PARTIAL_CONTENT if body.len() == to - from => Ok(body.to_vec()),
+            // Servers that ignore ranges send the whole file.
+            StatusCode::OK if body.len() == file_length => Ok(body[from..to].to_vec()),
+            status if !status.is_success() => anyhow::bail!("{url} answered {status}"),
+            _ => anyhow::bail!("{url} sent {} bytes instead of {}", body.len(), to - from),
+        }
+    }
+}
+
+/// Percent-encodes a path segment, leaving only unreserved characters as they are.
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push('%');
+            encoded.push_str(&hex::encode_upper([byte]));
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::storage::FileStorage;
+    use crate::test_support::{sample_torrent, test_torrent};
+    use crate::torrent::{TorrentBuilder, UrlList};
+
+    /// Serves `files` by path, honouring single range requests.
+    async fn server(files: HashMap<String, Vec<u8>>) -> String {
+        flaky_server(files, 0).await
+    }
+
+    /// Like [`server`], but answers the first `failures` requests with an error.
+    async fn flaky_server(files: HashMap<String, Vec<u8>>, failures: usize) -> String {
+        let files = Arc::new(files);
+        let failures = Arc::new(AtomicUsize::new(failures));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let files = Arc::clone(&files);
+                let failures = Arc::clone(&failures);
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0];
+                        if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        request.push(byte[0]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let path = request.split(' ').nth(1).unwrap();
+                    let range = request.lines().find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("range: bytes=")
+                            .map(String::from)
+                    });
+
+                    let fail = failures
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                        .is_ok();
+                    let (status, body) = match (files.get(path), range) {
+                        _ if fail => ("503 Service Unavailable", Vec::new()),
+                        (None, _) => ("404 Not Found", Vec::new()),
+                        (Some(file), None) => ("200 OK", file.clone()),
+                        (Some(file), Some(range)) => {
+                            let (from, to) = range.split_once('-').unwrap();
+                            let (from, to): (usize, usize) =
+                                (from.parse().unwrap(), to.parse().unwrap());
+                            ("206 Partial Content", file[from..=to].to_vec())
+                        }
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+
+        format!("http://{addr}/")
+    }
+
+    #[test]
+    fn maps_files_to_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("my content");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/b c"), b"b").unwrap();
+        let torrent = TorrentBuilder::new(&root)
+            .announce("http://tracker/announce")
+            .build()
+            .unwrap();
+        let file = &torrent.info.files()[0];
+
+        assert_eq!(
+            WebSeed::new("http://seed/files/").file_url(&torrent.info, file),
+            "http://seed/files/my%20content/sub/b%20c"
+        );
+        assert_eq!(
+            WebSeed::new("http://seed/files").file_url(&torrent.info, file),
+            "http://seed/files/my%20content/sub/b%20c"
+        );
+
+        let torrent = TorrentBuilder::new(root.join("sub/b c"))
+            .announce("http://tracker/announce")
+            .build()
+            .unwrap();
+        let file = &torrent.info.files()[0];
+        assert_eq!(
+            WebSeed::new("http://seed/files/").file_url(&torrent.info, file),
+            "http://seed/files/b%20c"
+        );
+        assert_eq!(
+            WebSeed::new("http://seed/b.bin").file_url(&torrent.info, file),
+            "http://seed/b.bin"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_pieces_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, a, b) = sample_torrent(&dir.path().join("content"));
+
+        let url = server(HashMap::from([
+            ("/content/a".to_string(), a.clone()),
+            ("/content/sub/b".to_string(), b.clone()),
+        ]))
+        .await;
+        let seed = WebSeed::new(url);
+
+        let content = [a, b].concat();
+        for piece in 0..torrent.info.pieces.len() {
+            let data = seed.fetch_piece(&torrent.info, piece).await.unwrap();
+            let start = piece * (1 << 14);
+            assert_eq!(data, content[start..start + data.len()]);
+        }
+
+        let seed = WebSeed::new(format!("{}missing/", seed.url()));
+        assert!(seed.fetch_piece(&torrent.info, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = vec![1; 10_000];
+        let c = vec![3; 10_000];
+        let torrent = test_torrent(
+            &dir.path().join("content"),
+            &[("a", &a), ("b", &[]), ("c", &c)],
+        );
+
+        // The server doesn't know about b, so a request for it would fail the piece.
+        let url = server(HashMap::from([
+            ("/content/a".to_string(), a.clone()),
+            ("/content/c".to_string(), c.clone()),
+        ]))
+        .await;
+        let data = WebSeed::new(url)
+            .fetch_piece(&torrent.info, 0)
+            .await
+            .unwrap();
+        assert_eq!(data, [&a[..], &c[..6384]].concat());
+    }
+
+    #[tokio::test]
+    async fn downloads_without_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let content: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        // Nothing listens on the tracker's port once the listener is dropped.
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+        drop(tracker);
+
+        let mut torrent = TorrentBuilder::new(&path)
+            .announce(announce)
+            .piece_length(1 << 14)
+            .build()
+            .unwrap();
+        // The seed is retried after errors rather than given up on.
+        let url = flaky_server(HashMap::from([("/file".to_string(), content.clone())]), 2).await;
+        torrent.url_list = Some(UrlList::Many(vec![format!("{url}file")]));
+
+        let output = dir.path().join("output");
+        let mut storage = FileStorage::create(&torrent.info, &output).unwrap();
+        torrent.download(&mut storage).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), content);
+    }
+}